@@ -0,0 +1,267 @@
+// Copyright 2019 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! debugger.rs - Interactive step debugger
+//! Runs a program one step at a time under the control of commands read from stdin. Source
+//! positions are shown and entered as one-based `line:column` pairs.
+
+use std::collections::BTreeSet;
+use std::io::{stdin, stdout, Write};
+
+use relambda::parse::Span;
+use relambda::{Encoding, Function, Status, StdIo, Vm};
+
+use crate::diagnostic;
+
+const HELP: &str = "\
+Commands:
+  s, step              run to the next source expression, entering applications
+  n, next              run to the next source expression, stepping over applications
+  f, finish            run until the current application returns
+  c, continue          run until a breakpoint is hit or the program terminates
+  b, break LINE:COL    stop whenever an expression starting at LINE:COL is executed
+  d, delete LINE:COL   remove a breakpoint
+  breakpoints          list breakpoints
+  p, stack             print the value stack, top first
+  bt, backtrace        print the return stack, innermost first
+  l, where             print the current location
+  q, quit              exit the debugger
+An empty line repeats the previous command. The program's own input is read from stdin too.";
+
+/// Longest rendering of a value or of source text that gets printed before being cut short.
+const MAX_DISPLAY_LEN: usize = 100;
+
+/// Debugs a file, returning the exit status.
+pub fn run(fname: &str, encoding: Encoding) -> i32 {
+    let source = match crate::read_source(fname, encoding) {
+        Some(source) => source,
+        None => return crate::EXIT_FAILURE,
+    };
+    let vm = match Vm::new(&source) {
+        Ok(vm) => vm,
+        Err(e) => {
+            eprint!("{}", diagnostic::render(fname, &source, &e));
+            return crate::EXIT_FAILURE;
+        }
+    };
+    let mut debugger = Debugger {
+        vm,
+        lines: source.lines().map(|l| l.chars().collect()).collect(),
+        fname: fname.to_string(),
+        source,
+        breakpoints: BTreeSet::new(),
+        terminated: false,
+        io: StdIo::with_encoding(encoding),
+    };
+    debugger.print_location();
+
+    let mut input = String::new();
+    let mut last_command = String::new();
+    loop {
+        print!("(debug) ");
+        stdout().flush().unwrap();
+        input.clear();
+        if stdin().read_line(&mut input).unwrap() == 0 {
+            return 0;
+        }
+        let command = match input.trim() {
+            "" => last_command.clone(),
+            c => c.to_string(),
+        };
+        if !debugger.execute(&command) {
+            return 0;
+        }
+        last_command = command;
+    }
+}
+
+struct Debugger {
+    vm: Vm,
+    lines: Vec<Vec<char>>,
+    /// The file being debugged and its contents, for error reports.
+    fname: String,
+    source: String,
+    breakpoints: BTreeSet<(usize, usize)>,
+    terminated: bool,
+    io: StdIo,
+}
+
+impl Debugger {
+    /// Executes a single command, returning false when the debugger should exit.
+    fn execute(&mut self, command: &str) -> bool {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let argument = words.next();
+        match (name, argument) {
+            ("s", None) | ("step", None) => self.resume(|_| true),
+            ("n", None) | ("next", None) => {
+                let depth = self.vm.return_stack().len();
+                self.resume(|vm| vm.return_stack().len() <= depth)
+            }
+            ("f", None) | ("finish", None) => {
+                let depth = self.vm.return_stack().len();
+                self.resume(|vm| vm.return_stack().len() < depth)
+            }
+            ("c", None) | ("continue", None) => self.resume(|_| false),
+            ("b", Some(pos)) | ("break", Some(pos)) => self.add_breakpoint(pos),
+            ("d", Some(pos)) | ("delete", Some(pos)) => match parse_position(pos) {
+                Some(p) if self.breakpoints.remove(&p) => (),
+                _ => println!("No breakpoint at {}", pos),
+            },
+            ("breakpoints", None) => {
+                for &(line, col) in &self.breakpoints {
+                    println!("{}:{}", line + 1, col + 1);
+                }
+            }
+            ("p", None) | ("stack", None) => {
+                for (i, value) in self.vm.stack().iter().rev().enumerate() {
+                    println!("{:>3}: {}", i, truncate(&value.to_unlambda(self.vm.code())));
+                }
+            }
+            ("bt", None) | ("backtrace", None) => {
+                for &(to, _) in self.vm.return_stack().iter().rev() {
+                    println!("  returns to {}", self.describe(to));
+                }
+            }
+            ("l", None) | ("where", None) => self.print_location(),
+            ("h", None) | ("help", None) => println!("{}", HELP),
+            ("q", None) | ("quit", None) => return false,
+            _ => println!("Unknown command `{}`, try `help`.", command),
+        }
+        true
+    }
+
+    /// Steps the program until it reaches a source expression for which `stop` returns true, it
+    /// hits a breakpoint, or it terminates. At least one step is always taken.
+    fn resume<F: Fn(&Vm) -> bool>(&mut self, stop: F) {
+        if self.terminated {
+            println!("The program is not running.");
+            return;
+        }
+        loop {
+            match self.vm.step(&mut self.io) {
+                Ok(Status::Running) | Ok(Status::NeedsInput) => (),
+                Ok(Status::Finished(value)) => return self.terminate("finished", &value),
+                Ok(Status::Exited(value)) => return self.terminate("exited through `e`", &value),
+                Err(e) => {
+                    eprint!("{}", diagnostic::render(&self.fname, &self.source, &e));
+                    self.terminated = true;
+                    return;
+                }
+            }
+            if let Some(span) = self.vm.span_at(self.vm.pc()) {
+                if self.breakpoints.contains(&span.start) {
+                    println!("Breakpoint hit.");
+                    break;
+                }
+                if stop(&self.vm) {
+                    break;
+                }
+            }
+        }
+        self.print_location();
+    }
+
+    fn terminate(&mut self, how: &str, value: &Function) {
+        println!(
+            "Program {} with value {}",
+            how,
+            truncate(&value.to_unlambda(self.vm.code()))
+        );
+        self.terminated = true;
+    }
+
+    fn add_breakpoint(&mut self, pos: &str) {
+        let position = match parse_position(pos) {
+            Some(p) => p,
+            None => {
+                println!("Expected a position as LINE:COL, got `{}`", pos);
+                return;
+            }
+        };
+        let exists = (0..self.vm.code().len())
+            .filter_map(|pc| self.vm.span_at(pc))
+            .any(|span| span.start == position);
+        if exists {
+            self.breakpoints.insert(position);
+        } else {
+            println!("No expression starts at {}", pos);
+        }
+    }
+
+    fn print_location(&self) {
+        if self.terminated {
+            return;
+        }
+        let pc = self.vm.pc();
+        println!("{:?} at {}", self.vm.code()[pc], self.describe(pc));
+    }
+
+    /// Describes the source expression the opcode at `pc` was compiled from.
+    fn describe(&self, pc: usize) -> String {
+        match self.vm.span_at(pc) {
+            Some(span) => format!(
+                "{}:{}: {}",
+                span.start.0 + 1,
+                span.start.1 + 1,
+                truncate(&self.source_text(span))
+            ),
+            None => format!("microcode (pc {})", pc),
+        }
+    }
+
+    /// The source text covered by `span`, with line breaks replaced by spaces.
+    fn source_text(&self, span: Span) -> String {
+        let mut text = String::new();
+        for line in span.start.0..=span.end.0 {
+            let chars = &self.lines[line];
+            let from = if line == span.start.0 {
+                span.start.1
+            } else {
+                0
+            };
+            let to = if line == span.end.0 {
+                span.end.1 + 1
+            } else {
+                chars.len()
+            };
+            if line != span.start.0 {
+                text.push(' ');
+            }
+            text.extend(&chars[from.min(chars.len())..to.min(chars.len())]);
+        }
+        text
+    }
+}
+
+/// Parses a one-based `line:column` pair into a zero-based position.
+fn parse_position(s: &str) -> Option<(usize, usize)> {
+    let mut parts = s.splitn(2, ':');
+    let line = parts.next()?.parse::<usize>().ok()?;
+    let col = parts.next()?.parse::<usize>().ok()?;
+    if line == 0 || col == 0 {
+        return None;
+    }
+    Some((line - 1, col - 1))
+}
+
+pub fn truncate(s: &str) -> String {
+    if s.chars().count() <= MAX_DISPLAY_LEN {
+        s.to_string()
+    } else {
+        let mut truncated: String = s.chars().take(MAX_DISPLAY_LEN).collect();
+        truncated.push_str("...");
+        truncated
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright 2019 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! diagnostic.rs - Error reports that quote the source
+//! Renders an `Error` the way rustc does: a headline, the one-based `file:line:column` location,
+//! and the source lines involved, with the offending characters underlined and labelled.
+
+use std::fmt::Write;
+
+use relambda::Error;
+
+/// An underlined region of a single source line. Positions are zero-based, as in `Error`.
+struct Label {
+    position: (usize, usize),
+    len: usize,
+    text: String,
+    primary: bool,
+}
+
+impl Label {
+    fn primary(position: (usize, usize), len: usize, text: &str) -> Self {
+        Self {
+            position,
+            len,
+            text: text.to_string(),
+            primary: true,
+        }
+    }
+
+    fn secondary(position: (usize, usize), text: &str) -> Self {
+        Self {
+            position,
+            len: 1,
+            text: text.to_string(),
+            primary: false,
+        }
+    }
+}
+
+pub fn render(fname: &str, source: &str, error: &Error) -> String {
+    let lines: Vec<Vec<char>> = source
+        .lines()
+        .map(|l| l.chars().map(|c| if c == '\t' { ' ' } else { c }).collect())
+        .collect();
+    let (message, mut labels) = describe(error, &lines);
+    let mut out = format!("error: {}\n", message);
+    let primary = match labels.iter().find(|l| l.primary) {
+        Some(label) => label.position,
+        None => return out,
+    };
+    labels.sort_by_key(|l| l.position);
+    let gutter = " ".repeat((labels.last().unwrap().position.0 + 1).to_string().len());
+    writeln!(
+        out,
+        "{}--> {}:{}:{}",
+        gutter,
+        fname,
+        primary.0 + 1,
+        primary.1 + 1
+    )
+    .unwrap();
+    writeln!(out, "{} |", gutter).unwrap();
+    let mut previous_line = None;
+    for label in &labels {
+        let line = label.position.0;
+        if previous_line != Some(line) {
+            if previous_line.is_some_and(|p| p + 1 < line) {
+                writeln!(out, "...").unwrap();
+            }
+            let text: String = lines
+                .get(line)
+                .map_or(String::new(), |l| l.iter().collect());
+            writeln!(out, "{:>width$} | {}", line + 1, text, width = gutter.len()).unwrap();
+            previous_line = Some(line);
+        }
+        let marker = if label.primary { "^" } else { "-" };
+        writeln!(
+            out,
+            "{} | {}{} {}",
+            gutter,
+            " ".repeat(label.position.1),
+            marker.repeat(label.len.max(1)),
+            label.text
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// The headline and labels for an error.
+fn describe(error: &Error, lines: &[Vec<char>]) -> (String, Vec<Label>) {
+    match error {
+        Error::UnexpectedEof { after, position } => {
+            let mut labels = vec![Label::primary(
+                end_of_input(lines),
+                1,
+                "expected more input",
+            )];
+            let secondary = match after {
+                Some('`') | Some('[') => Some("this application is missing an operand"),
+                Some('.') => Some("`.` needs a character to print"),
+                Some('?') => Some("`?` needs a character to compare"),
+                _ => None,
+            };
+            if let (Some(text), Some(position)) = (secondary, position) {
+                labels.push(Label::secondary(*position, text));
+            }
+            ("unexpected end of input".to_string(), labels)
+        }
+        Error::UnexpectedToken { ch, position } => (
+            format!("unexpected token `{}`", ch),
+            vec![Label::primary(
+                *position,
+                1,
+                "expected a combinator or an application",
+            )],
+        ),
+        Error::TrailingInput { ch, position } => (
+            format!("unexpected character `{}`", ch),
+            vec![Label::primary(
+                *position,
+                1,
+                "the program is already complete",
+            )],
+        ),
+        Error::UnboundReference { name, span } => (
+            format!("unbound reference `${}`", name),
+            vec![Label::primary(
+                span.start,
+                name.chars().count() + 1,
+                "nothing is defined with this name",
+            )],
+        ),
+        _ => {
+            // Runtime errors describe themselves fully, apart from where they happened.
+            let message = error.message();
+            // Spans that do not fit the source are clamped to it, or left out if their line is
+            // missing, so that a bad span still gives a report.
+            let labels = error.span().and_then(|span| {
+                let line = lines.get(span.start.0)?;
+                let len = if span.start.0 == span.end.0 {
+                    (span.end.1 + 1).saturating_sub(span.start.1)
+                } else {
+                    line.len().saturating_sub(span.start.1)
+                };
+                Some(Label::primary(
+                    span.start,
+                    len.max(1),
+                    "while evaluating this",
+                ))
+            });
+            (message, labels.into_iter().collect())
+        }
+    }
+}
+
+/// The position just after the last character of the last non-blank line.
+fn end_of_input(lines: &[Vec<char>]) -> (usize, usize) {
+    lines
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, l)| l.iter().any(|c| !c.is_whitespace()))
+        .map_or((0, 0), |(i, l)| (i, l.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use relambda::parse::{parse_chars, Span};
+    use relambda::{Error, Limit};
+
+    use super::render;
+
+    fn render_parse_error(source: &str) -> String {
+        render(
+            "prog.unl",
+            source,
+            &parse_chars(source.chars()).unwrap_err(),
+        )
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(
+            render_parse_error("``k"),
+            "\
+error: unexpected end of input
+ --> prog.unl:1:4
+  |
+1 | ``k
+  |  - this application is missing an operand
+  |    ^ expected more input
+"
+        );
+    }
+
+    #[test]
+    fn test_skipped_lines() {
+        assert_eq!(
+            render_parse_error("`\n# a\n# b\n\ti\n"),
+            "\
+error: unexpected end of input
+ --> prog.unl:4:3
+  |
+1 | `
+  | - this application is missing an operand
+...
+4 |  i
+  |   ^ expected more input
+"
+        );
+    }
+
+    #[test]
+    fn test_runtime_error() {
+        let error = Error::LimitExceeded {
+            limit: Limit::Steps,
+            steps: 3,
+            span: Some(Span {
+                start: (0, 1),
+                end: (1, 2),
+            }),
+        };
+        // Spans over several lines are underlined up to the end of their first line.
+        assert_eq!(
+            render("prog.unl", "``sii\n`ii", &error),
+            "\
+error: step limit exceeded after 3 steps
+ --> prog.unl:1:2
+  |
+1 | ``sii
+  |  ^^^^ while evaluating this
+"
+        );
+    }
+
+    #[test]
+    fn test_span_outside_source() {
+        let error = |start, end| Error::LimitExceeded {
+            limit: Limit::Steps,
+            steps: 3,
+            span: Some(Span { start, end }),
+        };
+        assert_eq!(
+            render("prog.unl", "`ii", &error((5, 0), (5, 2))),
+            "error: step limit exceeded after 3 steps\n"
+        );
+        assert_eq!(
+            render("prog.unl", "`ii\ni", &error((0, 7), (1, 0))),
+            "\
+error: step limit exceeded after 3 steps
+ --> prog.unl:1:8
+  |
+1 | `ii
+  |        ^ while evaluating this
+"
+        );
+    }
+}
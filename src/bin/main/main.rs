@@ -0,0 +1,443 @@
+// Copyright 2019 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, File};
+use std::io::{self, stdin, stdout, BufReader, Write};
+use std::process;
+use std::rc::Rc;
+
+use clap::{crate_version, App, Arg, ArgMatches, SubCommand};
+use log::Level;
+
+use relambda::parse::{
+    parse_chars, parse_cst, parse_reader_with_encoding, parse_recovering, CharPosIterator,
+};
+use relambda::unparse::{unparse_cst, Layout};
+use relambda::{
+    Encoding, Error, Function, Io, ReadWriteIo, RunLimits, Status, StdIo, StringIo, Termination, Vm,
+};
+
+mod debugger;
+mod diagnostic;
+mod repl;
+
+/// Exit status when the program could not be read or parsed, or the command line is invalid.
+const EXIT_FAILURE: i32 = 1;
+/// Exit status when the program stopped by applying `e`.
+const EXIT_E: i32 = 3;
+/// Exit status when the program went over a resource limit.
+const EXIT_LIMIT_EXCEEDED: i32 = 4;
+/// Exit status when reading input or writing output failed.
+const EXIT_IO_ERROR: i32 = 5;
+/// Exit status when `--exit-code` is used, but the program's value cannot be decoded.
+const EXIT_BAD_VALUE: i32 = 6;
+
+/// Number of opcodes a Church numeral may take to decode before it is taken not to be one.
+const CHURCH_MAX_STEPS: u64 = 1_000_000;
+
+/// How `--exit-code` turns the program's value into an exit status.
+#[derive(Debug, Clone, Copy)]
+enum ValueStatus {
+    /// The value is a Church numeral, whose number is the status.
+    Church,
+    /// `i` exits with 0 and `v` with 1.
+    Bool,
+}
+
+fn main() {
+    let args = match get_args() {
+        Some(args) => args,
+        None => process::exit(EXIT_FAILURE),
+    };
+    let status = match args.subcommand() {
+        ("debug", Some(debug_args)) => debugger::run(
+            debug_args.value_of("input_file").unwrap(),
+            encoding(debug_args),
+        ),
+        ("fmt", Some(fmt_args)) => {
+            let layout = if fmt_args.is_present("indented") {
+                Layout::Indented
+            } else {
+                Layout::Canonical
+            };
+            format_file(
+                fmt_args.value_of("input_file").unwrap(),
+                layout,
+                encoding(fmt_args),
+            )
+        }
+        ("check", Some(check_args)) => check_file(
+            check_args.value_of("input_file").unwrap(),
+            encoding(check_args),
+        ),
+        _ => {
+            let program = match (args.value_of("input_file"), args.value_of("program")) {
+                (Some("-"), _) => Some(Program::Stdin),
+                (Some(fname), _) => Some(Program::File(fname)),
+                (None, Some(code)) => Some(Program::Inline(code)),
+                (None, None) => None,
+            };
+            let input = match (args.value_of("input"), args.value_of("input_string")) {
+                (Some(fname), _) => Input::File(fname),
+                (None, Some(input)) => Input::String(input),
+                // Whatever follows the program on stdin was read ahead by the parser.
+                (None, None) if matches!(program, Some(Program::Stdin)) => Input::Empty,
+                (None, None) => Input::Stdin,
+            };
+            let value_status = match args.value_of("exit_code") {
+                Some("church") => Some(ValueStatus::Church),
+                Some("bool") => Some(ValueStatus::Bool),
+                _ => None,
+            };
+            match program {
+                Some(program) => run_program(program, input, encoding(&args), value_status),
+                None => match open_input(input, encoding(&args)) {
+                    Some(mut io) => repl::run(args.is_present("silent"), &mut *io, encoding(&args)),
+                    None => EXIT_FAILURE,
+                },
+            }
+        }
+    };
+    if status != 0 {
+        // Stdout may be what failed, so there is nothing to do about errors here.
+        let _ = stdout().flush();
+        process::exit(status);
+    }
+}
+
+/// Where the program to run comes from.
+#[derive(Debug, Clone, Copy)]
+enum Program<'a> {
+    File(&'a str),
+    Stdin,
+    Inline(&'a str),
+}
+
+impl<'a> Program<'a> {
+    fn name(self) -> &'a str {
+        match self {
+            Program::File(fname) => fname,
+            Program::Stdin => "<stdin>",
+            Program::Inline(_) => "<command line>",
+        }
+    }
+}
+
+/// Where `@` reads from.
+#[derive(Debug, Clone, Copy)]
+enum Input<'a> {
+    Stdin,
+    File(&'a str),
+    String(&'a str),
+    Empty,
+}
+
+impl<'a> Input<'a> {
+    fn open(self, encoding: Encoding) -> io::Result<Box<dyn Io + 'a>> {
+        Ok(match self {
+            Input::Stdin => Box::new(StdIo::with_encoding(encoding)),
+            Input::File(fname) => Box::new(ReadWriteIo::with_encoding(
+                BufReader::new(File::open(fname)?),
+                stdout(),
+                encoding,
+            )),
+            Input::String(input) => Box::new(ReadWriteIo::with_encoding(
+                input.as_bytes(),
+                stdout(),
+                encoding,
+            )),
+            Input::Empty => Box::new(ReadWriteIo::with_encoding(io::empty(), stdout(), encoding)),
+        })
+    }
+}
+
+/// Opens the source of input for `@`, reporting any error.
+fn open_input(input: Input, encoding: Encoding) -> Option<Box<dyn Io + '_>> {
+    match input.open(encoding) {
+        Ok(io) => Some(io),
+        Err(e) => {
+            if let Input::File(fname) = input {
+                eprintln!("error: cannot read {}: {}", fname, e);
+            }
+            None
+        }
+    }
+}
+
+/// Runs a program, parsing it as it is read so that it never has to be in memory as a whole.
+/// Returns the exit status for the way the program terminated, or for its value if
+/// `value_status` is set.
+fn run_program(
+    program: Program,
+    input: Input,
+    encoding: Encoding,
+    value_status: Option<ValueStatus>,
+) -> i32 {
+    let st = match program {
+        Program::File(fname) => File::open(fname)
+            .map_err(Error::from)
+            .and_then(|f| parse_reader_with_encoding(f, encoding)),
+        Program::Stdin => parse_reader_with_encoding(stdin(), encoding),
+        Program::Inline(code) => parse_chars(code.chars()),
+    };
+    let mut vm = match st.and_then(|st| Vm::from_syntax_tree(&st)) {
+        Ok(vm) => vm,
+        // Only reading the program can fail this way before it runs.
+        Err(Error::Io { error, .. }) => {
+            eprintln!("error: cannot read {}: {}", program.name(), error);
+            return EXIT_FAILURE;
+        }
+        Err(e) => {
+            report(program, encoding, &e);
+            return EXIT_FAILURE;
+        }
+    };
+    let mut io = match open_input(input, encoding) {
+        Some(io) => io,
+        None => return EXIT_FAILURE,
+    };
+    let termination = vm.run_to_termination(&mut *io);
+    let status = match (&termination, value_status) {
+        (Termination::Finished(v), Some(mode)) | (Termination::Exited(v), Some(mode)) => {
+            decode_status(&mut vm, v.clone(), mode)
+        }
+        (Termination::Finished(_), None) => 0,
+        (Termination::Exited(_), None) => EXIT_E,
+        (Termination::LimitExceeded { .. }, _) => EXIT_LIMIT_EXCEEDED,
+        (Termination::Io { .. }, _) => EXIT_IO_ERROR,
+        (Termination::Interrupted { .. }, _) => {
+            unreachable!("programs run from files are never interrupted")
+        }
+    };
+    if let Err(e) = termination.into_result() {
+        report(program, encoding, &e);
+    }
+    status
+}
+
+/// Prints an error about a program to stderr, quoting its source if it is still available.
+fn report(program: Program, encoding: Encoding, e: &Error) {
+    let contents = match program {
+        Program::File(fname) => fs::read(fname)
+            .and_then(|bytes| encoding.decode(bytes))
+            .unwrap_or_default(),
+        Program::Stdin => String::new(),
+        Program::Inline(code) => code.to_string(),
+    };
+    eprint!("{}", diagnostic::render(program.name(), &contents, e));
+}
+
+/// Turns the value a program terminated with into an exit status, for `--exit-code`.
+fn decode_status(vm: &mut Vm, value: Rc<Function>, mode: ValueStatus) -> i32 {
+    let status = match mode {
+        ValueStatus::Bool => match *value {
+            Function::I => Some(0),
+            Function::V => Some(1),
+            _ => None,
+        },
+        // Exit statuses above 255 would wrap around, possibly to 0.
+        ValueStatus::Church => church_to_int(vm, value).map(|n| n.min(255) as i32),
+    };
+    status.unwrap_or_else(|| {
+        let expected = match mode {
+            ValueStatus::Bool => "`i` or `v`",
+            ValueStatus::Church => "a Church numeral",
+        };
+        eprintln!("error: the program's value is not {}", expected);
+        EXIT_BAD_VALUE
+    })
+}
+
+/// Decodes a Church numeral by applying it to `.*` and `i`, then counting the stars printed. The
+/// result must be the very `i` passed in, and decoding must finish within `CHURCH_MAX_STEPS`.
+fn church_to_int(vm: &mut Vm, numeral: Rc<Function>) -> Option<u64> {
+    let mut io = StringIo::new("");
+    vm.set_limits(RunLimits {
+        max_steps: Some(vm.steps() + CHURCH_MAX_STEPS),
+        ..RunLimits::default()
+    });
+    vm.apply(numeral, Rc::new(Function::Dot('*')));
+    let repeat = match vm.run(&mut io) {
+        Ok(Status::Finished(f)) => f,
+        _ => return None,
+    };
+    let zero = Rc::new(Function::I);
+    vm.apply(repeat, zero.clone());
+    match vm.run(&mut io) {
+        Ok(Status::Finished(f))
+            if Rc::ptr_eq(&f, &zero) && io.output().chars().all(|c| c == '*') =>
+        {
+            Some(io.output().len() as u64)
+        }
+        _ => None,
+    }
+}
+
+/// Reads a whole source file, reporting any error.
+fn read_source(fname: &str, encoding: Encoding) -> Option<String> {
+    match fs::read(fname).and_then(|bytes| encoding.decode(bytes)) {
+        Ok(contents) => Some(contents),
+        Err(e) => {
+            eprintln!("error: cannot read {}: {}", fname, e);
+            None
+        }
+    }
+}
+
+fn format_file(fname: &str, layout: Layout, encoding: Encoding) -> i32 {
+    let contents = match read_source(fname, encoding) {
+        Some(contents) => contents,
+        None => return EXIT_FAILURE,
+    };
+    let cst = match parse_cst(&mut CharPosIterator::new(contents.chars()).peekable()) {
+        Ok(cst) => cst,
+        Err(e) => {
+            eprint!("{}", diagnostic::render(fname, &contents, &e));
+            return EXIT_FAILURE;
+        }
+    };
+    let formatted = unparse_cst(&cst, layout) + "\n";
+    stdout()
+        .write_all(&encoding.encode(&formatted).unwrap())
+        .unwrap();
+    0
+}
+
+/// Lists every syntax error in a file, failing if there are any.
+fn check_file(fname: &str, encoding: Encoding) -> i32 {
+    let contents = match read_source(fname, encoding) {
+        Some(contents) => contents,
+        None => return EXIT_FAILURE,
+    };
+    let (_, errors) = parse_recovering(&mut CharPosIterator::new(contents.chars()).peekable());
+    for e in &errors {
+        eprint!("{}", diagnostic::render(fname, &contents, e));
+    }
+    if errors.is_empty() {
+        0
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+fn encoding(args: &ArgMatches) -> Encoding {
+    match args.value_of("encoding") {
+        Some("latin-1") => Encoding::Latin1,
+        _ => Encoding::Utf8,
+    }
+}
+
+fn get_args() -> Option<ArgMatches<'static>> {
+    let matches = App::new("relambda")
+        .version(crate_version!())
+        .arg(Arg::with_name("input_file").help(
+            "File to execute, or - to read the program from stdin. If not set, will start a REPL.",
+        ))
+        .arg(
+            Arg::with_name("program")
+                .short("e")
+                .takes_value(true)
+                .value_name("PROGRAM")
+                .conflicts_with("input_file")
+                .help("Program to execute, given on the command line."),
+        )
+        .arg(
+            Arg::with_name("input")
+                .long("input")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("File for `@` to read from, instead of stdin."),
+        )
+        .arg(
+            Arg::with_name("input_string")
+                .long("input-string")
+                .takes_value(true)
+                .value_name("STRING")
+                .conflicts_with("input")
+                .help("Text for `@` to read, instead of stdin."),
+        )
+        .arg(
+            Arg::with_name("silent")
+                .short("s")
+                .long("silent")
+                .help("If in REPL mode, controls whether to show prompts and return values."),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .help("Print debugging information."),
+        )
+        .arg(
+            Arg::with_name("exit_code")
+                .long("exit-code")
+                .takes_value(true)
+                .possible_values(&["church", "bool"])
+                .help("Exit with a status decoded from the program's value: the number of a Church numeral, or 0 for `i` and 1 for `v`."),
+        )
+        .arg(
+            Arg::with_name("encoding")
+                .long("encoding")
+                .takes_value(true)
+                .possible_values(&["utf-8", "latin-1"])
+                .default_value("utf-8")
+                .global(true)
+                .help("Encoding of source files, program input and program output. With latin-1, every byte is one character."),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Runs a program under an interactive step debugger.")
+                .arg(
+                    Arg::with_name("input_file")
+                        .required(true)
+                        .help("File to debug."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Prints a program in a normalized layout.")
+                .arg(
+                    Arg::with_name("indented").short("i").long("indented").help(
+                        "Puts each operand on its own line instead of using the shortest form.",
+                    ),
+                )
+                .arg(
+                    Arg::with_name("input_file")
+                        .required(true)
+                        .help("File to format."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Lists all syntax errors in a program without running it.")
+                .arg(
+                    Arg::with_name("input_file")
+                        .required(true)
+                        .help("File to check."),
+                ),
+        )
+        .get_matches();
+    if (matches.is_present("input_file") || matches.is_present("program"))
+        && matches.is_present("silent")
+    {
+        eprintln!("--silent cannot be used with an input file.");
+        return None;
+    }
+    let verbosity = if matches.is_present("verbose") {
+        Level::Debug as usize
+    } else {
+        0
+    };
+    stderrlog::new().verbosity(verbosity).init().unwrap();
+    Some(matches)
+}
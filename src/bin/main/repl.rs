@@ -0,0 +1,478 @@
+// Copyright 2019 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! repl.rs - Interactive interpreter
+//! Evaluates terms read from stdin one at a time, printing their values. Lines starting with `:`
+//! are commands, which mostly manage named definitions that later terms can refer to as `$name`.
+//! Results are kept as `$1`, `$2`, ... and `$_` for the latest one. Unlike definitions, which are
+//! terms evaluated anew wherever they are used, results are the values themselves: all terms run
+//! on the same `Vm`, so even promises and continuations can be used again.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{stdin, IsTerminal};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use relambda::parse::{is_name_char, parse_chars, Application, Span, SyntaxTree};
+use relambda::unparse::{unparse, Layout};
+use relambda::{Encoding, Error, InterruptHandle, Io, Status, Vm};
+
+use crate::debugger::truncate;
+use crate::diagnostic;
+
+const PROMPT: &str = ">> ";
+/// Prompt for the following lines of a term that is still missing operands.
+const CONTINUATION_PROMPT: &str = ".. ";
+
+const HELP: &str = "\
+Commands:
+  :def NAME = TERM     define $NAME as TERM, which may refer to earlier definitions
+  :defs                list definitions
+  :undef NAME          remove a definition
+  :save FILE           write definitions to FILE
+  :load FILE           run the commands in FILE, such as those written by :save
+  :tree TERM           print the syntax tree of TERM
+  :disasm TERM         print the opcodes TERM compiles to
+  :trace TERM          evaluate TERM, printing each opcode as it runs along with the stack
+  :time TERM           evaluate TERM and print how long it took
+  :steps TERM          evaluate TERM and print how many opcodes it ran
+  :help                print this message
+Anything else is a term to evaluate. Its value can be used later as $N, where N is the number
+printed along with it, or as $_ until the next one. Ctrl-C stops a running term, or discards
+the term being typed. Type exit or end the input to quit.";
+
+/// Runs the REPL until the input ends, returning the exit status.
+pub fn run(silent: bool, io: &mut dyn Io, encoding: Encoding) -> i32 {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("error: cannot set up the terminal: {}", e);
+            return crate::EXIT_FAILURE;
+        }
+    };
+    let history = history_file().filter(|_| stdin().is_terminal());
+    if let Some(history) = &history {
+        // There is no history yet the first time around.
+        let _ = editor.load_history(history);
+    }
+    let mut session = Session {
+        definitions: BTreeMap::new(),
+        vm: None,
+        results: 0,
+        interrupt: InterruptHandle::new(),
+        io,
+        encoding,
+        silent,
+    };
+    let interrupt = session.interrupt.clone();
+    if let Err(e) = ctrlc::set_handler(move || interrupt.interrupt()) {
+        eprintln!("warning: Ctrl-C will exit instead of stopping terms: {}", e);
+    }
+    let mut next_line = |prompt: &str| {
+        let line = editor.readline(if silent { "" } else { prompt })?;
+        editor.add_history_entry(line.as_str())?;
+        Ok(line)
+    };
+    while let Some(entry) = read_entry(&mut next_line) {
+        if entry.trim().eq_ignore_ascii_case("exit") {
+            break;
+        }
+        session.handle(&entry);
+    }
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!(
+                "warning: cannot save history to {}: {}",
+                history.display(),
+                e
+            );
+        }
+    }
+    0
+}
+
+fn history_file() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".relambda_history"))
+}
+
+/// Reads lines until they make up a whole entry, so that terms can span lines. Lines with only
+/// whitespace and comments are skipped, and Ctrl-C discards the lines read so far. Returns `None`
+/// once there are no more lines.
+fn read_entry(mut next_line: impl FnMut(&str) -> Result<String, ReadlineError>) -> Option<String> {
+    let mut entry = String::new();
+    loop {
+        let prompt = if entry.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        let line = match next_line(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                entry.clear();
+                continue;
+            }
+            Err(_) => return Some(entry).filter(|entry| !entry.is_empty()),
+        };
+        entry.push_str(&line);
+        entry.push('\n');
+        match term_of(&entry).map(|term| parse_chars(term.chars())) {
+            Some(Err(Error::UnexpectedEof { after: None, .. })) if !is_command(&entry) => {
+                entry.clear()
+            }
+            Some(Err(Error::UnexpectedEof { .. })) => (),
+            _ => return Some(entry),
+        }
+    }
+}
+
+fn is_command(entry: &str) -> bool {
+    entry.trim_start().starts_with(':')
+}
+
+/// The part of an entry that is a term, if any.
+fn term_of(entry: &str) -> Option<&str> {
+    let (name, args) = match entry.trim_start().strip_prefix(':') {
+        None => return Some(entry),
+        Some(command) => split_command(command),
+    };
+    match name {
+        "def" => args.split_once('=').map(|(_, term)| term),
+        "tree" | "disasm" | "trace" | "time" | "steps" => Some(args),
+        _ => None,
+    }
+}
+
+/// Splits a command, without its `:`, into its name and arguments.
+fn split_command(command: &str) -> (&str, &str) {
+    command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""))
+}
+
+/// How to evaluate a term.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Mode {
+    Run,
+    Trace,
+    Time,
+    Steps,
+}
+
+struct Session<'a> {
+    /// Definitions, with the references they contained already replaced.
+    definitions: BTreeMap<String, SyntaxTree>,
+    /// The VM every term runs on, once there has been one, with results bound to their names.
+    vm: Option<Vm>,
+    /// Number of results so far.
+    results: usize,
+    /// Interrupted by Ctrl-C.
+    interrupt: InterruptHandle,
+    io: &'a mut dyn Io,
+    encoding: Encoding,
+    silent: bool,
+}
+
+/// Why an entry could not be handled.
+enum Failure {
+    /// An error in a term, along with the term's text, which the error's positions point into.
+    Term(String, Error),
+    Message(String),
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::Message(message)
+    }
+}
+
+impl From<&str> for Failure {
+    fn from(message: &str) -> Self {
+        Failure::Message(message.to_string())
+    }
+}
+
+/// Attributes an error to the term it happened in.
+fn in_term(term: &str) -> impl FnOnce(Error) -> Failure + '_ {
+    move |e| Failure::Term(term.to_string(), e)
+}
+
+impl Session<'_> {
+    fn handle(&mut self, entry: &str) {
+        let result = match entry.trim().strip_prefix(':') {
+            Some(command) => self.command(command),
+            None => self.evaluate(entry, Mode::Run),
+        };
+        match result {
+            Ok(()) => (),
+            Err(Failure::Term(term, e)) => eprint!("{}", diagnostic::render("<repl>", &term, &e)),
+            Err(Failure::Message(message)) => eprintln!("error: {}", message),
+        }
+    }
+
+    fn command(&mut self, command: &str) -> Result<(), Failure> {
+        let (name, args) = split_command(command);
+        let args = args.trim();
+        match name {
+            "def" => {
+                let (name, term) = args.split_once('=').ok_or("usage: :def NAME = TERM")?;
+                let name = parse_name(name)?;
+                if is_result_name(name) {
+                    return Err(format!("`${}` is reserved for results", name).into());
+                }
+                let st = self.parse_definition(term)?;
+                self.definitions.insert(name.to_string(), st);
+            }
+            "defs" => {
+                for (name, st) in &self.definitions {
+                    println!("{} = {}", name, unparse(st, Layout::Canonical));
+                }
+            }
+            "undef" => {
+                let name = parse_name(args)?;
+                if self.definitions.remove(name).is_none() {
+                    return Err(format!("`${}` is not defined", name).into());
+                }
+            }
+            "save" => {
+                let contents: String = self
+                    .definitions
+                    .iter()
+                    .map(|(name, st)| {
+                        format!(":def {} = {}\n", name, unparse(st, Layout::Canonical))
+                    })
+                    .collect();
+                self.encoding
+                    .encode(&contents)
+                    .and_then(|bytes| fs::write(args, bytes))
+                    .map_err(|e| format!("cannot write {}: {}", args, e))?;
+            }
+            "load" => {
+                let contents = fs::read(args)
+                    .and_then(|bytes| self.encoding.decode(bytes))
+                    .map_err(|e| format!("cannot read {}: {}", args, e))?;
+                let mut lines = contents.lines().map(str::to_string);
+                while let Some(entry) = read_entry(|_| lines.next().ok_or(ReadlineError::Eof)) {
+                    self.handle(&entry);
+                }
+            }
+            "tree" => print_tree(&self.parse(args).map_err(in_term(args))?),
+            "disasm" => {
+                let st = self.parse(args).map_err(in_term(args))?;
+                let vm = self.load(&st).map_err(in_term(args))?;
+                for pc in vm.pc()..vm.code().len() {
+                    let span = vm.span_at(pc).map_or(String::new(), |span| {
+                        format!("{}:{}", span.start.0 + 1, span.start.1 + 1)
+                    });
+                    let opcode = format!("{:?}", vm.code()[pc]);
+                    let line = format!("{:>6}  {:<24} {}", pc, opcode, span);
+                    println!("{}", line.trim_end());
+                }
+            }
+            "trace" => self.evaluate(args, Mode::Trace)?,
+            "time" => self.evaluate(args, Mode::Time)?,
+            "steps" => self.evaluate(args, Mode::Steps)?,
+            "help" => println!("{}", HELP),
+            _ => return Err(format!("unknown command `:{}`, try :help", name).into()),
+        }
+        Ok(())
+    }
+
+    fn evaluate(&mut self, term: &str, mode: Mode) -> Result<(), Failure> {
+        let st = self.parse(term).map_err(in_term(term))?;
+        self.load(&st).map_err(in_term(term))?;
+        let (vm, io) = (self.vm.as_mut().unwrap(), &mut *self.io);
+        let (start, steps) = (Instant::now(), vm.steps());
+        // Ctrl-C only stops terms while they run.
+        self.interrupt.reset();
+        let status = match run_term(vm, io, mode == Mode::Trace) {
+            Err(Error::Interrupted { .. }) => {
+                return Err(format!("interrupted after {} steps", vm.steps() - steps).into());
+            }
+            status => status.map_err(in_term(term))?,
+        };
+        let elapsed = start.elapsed();
+        match status {
+            Status::Finished(v) | Status::Exited(v) => {
+                self.results += 1;
+                if !self.silent {
+                    println!("${} => {}", self.results, vm.to_unlambda(&v));
+                }
+                vm.bind(&self.results.to_string(), v.clone());
+                vm.bind("_", v);
+            }
+            status => unreachable!("unexpected status {:?} with blocking input", status),
+        }
+        match mode {
+            Mode::Time => println!("took {:?}", elapsed),
+            Mode::Steps => println!("ran {} opcodes", vm.steps() - steps),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Compiles a tree on the session's VM, without running it.
+    fn load(&mut self, st: &SyntaxTree) -> Result<&Vm, Error> {
+        match &mut self.vm {
+            Some(vm) => vm.load(st)?,
+            None => {
+                let mut vm = Vm::from_syntax_tree(st)?;
+                vm.set_interrupt_handle(self.interrupt.clone());
+                self.vm = Some(vm);
+            }
+        }
+        Ok(self.vm.as_ref().unwrap())
+    }
+
+    /// Parses a term, replacing references with what they are defined as. References to results
+    /// are left for the VM.
+    fn parse(&self, term: &str) -> Result<SyntaxTree, Error> {
+        let is_result = |name: &str| self.vm.as_ref().is_some_and(|vm| vm.is_bound(name));
+        expand(&parse_chars(term.chars())?, &self.definitions, &is_result)
+    }
+
+    /// Parses the term of a definition. Definitions cannot refer to results: those are values
+    /// rather than terms, so `:save` could not write them out.
+    fn parse_definition(&self, term: &str) -> Result<SyntaxTree, Failure> {
+        let st = parse_chars(term.chars()).map_err(in_term(term))?;
+        expand(&st, &self.definitions, &|_| false).map_err(|e| match e {
+            Error::UnboundReference { name, .. } if is_result_name(&name) => format!(
+                "definitions cannot refer to results such as `${}`, only to other definitions",
+                name
+            )
+            .into(),
+            e => in_term(term)(e),
+        })
+    }
+}
+
+/// Whether a name is one results are kept under, `_` or a number.
+fn is_result_name(name: &str) -> bool {
+    name == "_" || name.chars().all(|c| c.is_ascii_digit())
+}
+
+fn parse_name(name: &str) -> Result<&str, String> {
+    let name = name.trim();
+    let name = name.strip_prefix('$').unwrap_or(name);
+    if name.is_empty() || !name.chars().all(is_name_char) {
+        return Err(format!(
+            "`{}` is not a valid name, use letters, digits and underscores",
+            name
+        ));
+    }
+    Ok(name)
+}
+
+/// Replaces references with their definitions, keeping those that `keep` accepts as they are.
+/// The spans of a definition point into the text it was defined with, so every node pasted in from
+/// one gets the span of the reference instead, and errors in it are blamed on the reference.
+fn expand(
+    st: &SyntaxTree,
+    definitions: &BTreeMap<String, SyntaxTree>,
+    keep: &dyn Fn(&str) -> bool,
+) -> Result<SyntaxTree, Error> {
+    // Trees still to copy, along with the span of the reference they were pasted in for, if any,
+    // and applications whose operands have been copied, in the order their trees are needed.
+    // Definitions are walked like the rest rather than cloned, so that neither recurses on deeply
+    // nested terms.
+    enum Step<'a> {
+        Tree(&'a SyntaxTree, Option<Span>),
+        Apply(Span),
+    }
+    let mut steps = vec![Step::Tree(st, None)];
+    let mut trees = Vec::new();
+    while let Some(step) = steps.pop() {
+        match step {
+            Step::Tree(SyntaxTree::Reference(name, span), at) if !keep(name) => {
+                let definition = definitions
+                    .get(name)
+                    .ok_or_else(|| Error::UnboundReference {
+                        name: name.clone(),
+                        span: *span,
+                    })?;
+                steps.push(Step::Tree(definition, at.or(Some(*span))));
+            }
+            Step::Tree(SyntaxTree::Application(Application { func, arg, span }), at) => {
+                steps.push(Step::Apply(at.unwrap_or(*span)));
+                steps.push(Step::Tree(arg, at));
+                steps.push(Step::Tree(func, at));
+            }
+            Step::Tree(st, None) => trees.push(st.clone()),
+            Step::Tree(st, Some(at)) => trees.push(match st {
+                SyntaxTree::Combinator(c, _) => SyntaxTree::Combinator(*c, at),
+                SyntaxTree::Reference(name, _) => SyntaxTree::Reference(name.clone(), at),
+                SyntaxTree::Error(_) => SyntaxTree::Error(at),
+                SyntaxTree::Application(_) => unreachable!("applications are walked above"),
+            }),
+            Step::Apply(span) => {
+                let arg = trees.pop().unwrap();
+                let func = trees.pop().unwrap();
+                trees.push(SyntaxTree::Application(Application {
+                    func: Box::new(func),
+                    arg: Box::new(arg),
+                    span,
+                }));
+            }
+        }
+    }
+    Ok(trees.pop().unwrap())
+}
+
+/// Runs a program until it terminates. With `trace`, each opcode is printed before it runs, along
+/// with the size and top of the stack.
+fn run_term(vm: &mut Vm, io: &mut dyn Io, trace: bool) -> Result<Status, Error> {
+    if !trace {
+        return vm.run(io);
+    }
+    loop {
+        let pc = vm.pc();
+        let top = vm
+            .stack()
+            .last()
+            .map_or(String::new(), |v| truncate(&vm.to_unlambda(v)));
+        let opcode = format!("{:?}", vm.code()[pc]);
+        let line = format!("{:>6}  {:<24} [{}] {}", pc, opcode, vm.stack().len(), top);
+        println!("{}", line.trim_end());
+        match vm.step(io)? {
+            Status::Running => (),
+            status => return Ok(status),
+        }
+    }
+}
+
+/// Prints a syntax tree, one node per line with its one-based source position.
+fn print_tree(st: &SyntaxTree) {
+    let position = |span: Span| format!("{}:{}", span.start.0 + 1, span.start.1 + 1);
+    let mut pending = vec![(st, 0)];
+    while let Some((st, depth)) = pending.pop() {
+        let indent = "  ".repeat(depth);
+        match st {
+            SyntaxTree::Combinator(c, span) => println!("{}{:?} {}", indent, c, position(*span)),
+            SyntaxTree::Reference(name, span) => {
+                println!("{}${} {}", indent, name, position(*span))
+            }
+            SyntaxTree::Error(span) => println!("{}<error> {}", indent, position(*span)),
+            SyntaxTree::Application(Application { func, arg, span }) => {
+                println!("{}Application {}", indent, position(*span));
+                pending.push((arg, depth + 1));
+                pending.push((func, depth + 1));
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2019 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::io;
+
+use crate::parse::Span;
+use crate::Limit;
+
+/// Everything that can go wrong while parsing, compiling or running a program.
+///
+/// Positions are zero-based `(line, column)` pairs, as produced by `CharPosIterator`. Errors
+/// raised while running carry the span of the expression being executed, if it has one; the
+/// microcode that implements `s` and `d` does not.
+#[derive(Debug)]
+pub enum Error {
+    /// The input ended while a term was still expected. `after` and `position` identify the token
+    /// that was waiting for more input, if any: a `.` or `?` missing its character, or a
+    /// backquote missing an operand.
+    UnexpectedEof {
+        after: Option<char>,
+        position: Option<(usize, usize)>,
+    },
+    /// A character that cannot start a term.
+    UnexpectedToken { ch: char, position: (usize, usize) },
+    /// Input left over after a complete program was read.
+    TrailingInput { ch: char, position: (usize, usize) },
+    /// A `SyntaxTree::Error` was found while compiling.
+    Syntax { span: Span },
+    /// A `$name` reference to a name that is not bound with `Vm::bind` was found while compiling.
+    UnboundReference { name: String, span: Span },
+    /// Reading input or writing output failed while the program was running.
+    Io {
+        error: io::Error,
+        span: Option<Span>,
+    },
+    /// The program was stopped because it went over one of its `RunLimits`, after executing
+    /// `steps` opcodes.
+    LimitExceeded {
+        limit: Limit,
+        steps: u64,
+        span: Option<Span>,
+    },
+    /// The program was stopped through an `InterruptHandle`, after executing `steps` opcodes.
+    Interrupted { steps: u64, span: Option<Span> },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())?;
+        match self.position() {
+            Some(position) => write!(f, " at {:?}", position),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error {
+    /// Describes the error without saying where it happened, for callers that show the location
+    /// their own way. `Display` gives the same text followed by the position.
+    pub fn message(&self) -> String {
+        match self {
+            Error::UnexpectedEof {
+                after: Some(ch),
+                position: Some(_),
+            } => format!("unexpected EOF after `{}`", ch),
+            Error::UnexpectedEof { .. } => "unexpected EOF".to_string(),
+            Error::UnexpectedToken { ch, .. } => format!("unexpected token `{}`", ch),
+            Error::TrailingInput { ch, .. } => format!("unexpected character `{}`", ch),
+            Error::Syntax { .. } => "cannot compile a term that failed to parse".to_string(),
+            Error::UnboundReference { name, .. } => format!("unbound reference `${}`", name),
+            Error::Io { error, .. } => format!("I/O error: {}", error),
+            Error::LimitExceeded { limit, steps, .. } => {
+                format!("{} exceeded after {} steps", limit, steps)
+            }
+            Error::Interrupted { steps, .. } => format!("interrupted after {} steps", steps),
+        }
+    }
+
+    /// Where the error happened, if anywhere in particular.
+    fn position(&self) -> Option<(usize, usize)> {
+        match self {
+            Error::UnexpectedEof {
+                after: Some(_),
+                position,
+            } => *position,
+            Error::UnexpectedEof { .. } => None,
+            Error::UnexpectedToken { position, .. } | Error::TrailingInput { position, .. } => {
+                Some(*position)
+            }
+            _ => self.span().map(|span| span.start),
+        }
+    }
+
+    /// The span of the expression that was executing when a runtime error occurred, or of the
+    /// term that could not be compiled.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::Syntax { span } | Error::UnboundReference { span, .. } => Some(*span),
+            Error::Io { span, .. }
+            | Error::LimitExceeded { span, .. }
+            | Error::Interrupted { span, .. } => *span,
+            _ => None,
+        }
+    }
+
+    /// Attaches a span to a runtime error that does not have one yet.
+    pub(crate) fn at(mut self, at: Option<Span>) -> Self {
+        match &mut self {
+            Error::Io { span, .. }
+            | Error::LimitExceeded { span, .. }
+            | Error::Interrupted { span, .. }
+                if span.is_none() =>
+            {
+                *span = at
+            }
+            _ => (),
+        }
+        self
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io {
+            error: e,
+            span: None,
+        }
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright 2019 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! io.rs - Input and output backends
+//! The VM never touches stdin or stdout itself. Everything `.x`, `@`, `|` and `?x` need goes
+//! through an `Io` implementation, so programs can run against the terminal, in-memory buffers,
+//! or any pair of `Read`/`Write` streams.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{self, stdin, stdout, Read, Write};
+
+use unicode_reader::CodePoints;
+
+/// Character-level I/O used by the VM.
+pub trait Io {
+    /// Reads a single character, or returns `None` once the input is exhausted. Non-blocking
+    /// implementations return an `ErrorKind::WouldBlock` error when no input is available yet.
+    fn read_char(&mut self) -> io::Result<Option<char>>;
+    /// Writes a single character.
+    fn write_char(&mut self, ch: char) -> io::Result<()>;
+    /// Flushes any buffered output. The VM calls this before reading and when the program stops.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// How characters are turned into bytes and back when talking to byte streams. This applies to
+/// everything `.x`, `@`, `?x` and `|` do, so that a program sees the same character whichever way
+/// it came in or goes out.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// One byte per character, U+0000 to U+00FF, so that any byte can be read and written as is.
+    /// Writing a character above U+00FF fails with `ErrorKind::InvalidData`.
+    Latin1,
+}
+
+impl Encoding {
+    // Reading byte by byte is deliberate: any read-ahead would swallow input that belongs to
+    // whoever else shares the reader, such as the REPL reading its next line from stdin.
+    #[allow(clippy::unbuffered_bytes)]
+    pub(crate) fn read_char<R: Read>(self, reader: &mut R) -> io::Result<Option<char>> {
+        match self {
+            Encoding::Utf8 => CodePoints::from(reader.bytes()).next().transpose(),
+            Encoding::Latin1 => Ok(reader.bytes().next().transpose()?.map(char::from)),
+        }
+    }
+
+    pub(crate) fn write_char<W: Write>(self, writer: &mut W, ch: char) -> io::Result<()> {
+        match self {
+            Encoding::Utf8 => writer.write_all(ch.encode_utf8(&mut [0; 4]).as_bytes()),
+            Encoding::Latin1 => match u8::try_from(ch) {
+                Ok(byte) => writer.write_all(&[byte]),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("`{}` cannot be written as Latin-1", ch),
+                )),
+            },
+        }
+    }
+
+    /// Decodes a whole buffer, such as the contents of a source file.
+    pub fn decode(self, bytes: Vec<u8>) -> io::Result<String> {
+        match self {
+            Encoding::Utf8 => {
+                String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Encoding::Latin1 => Ok(bytes.into_iter().map(char::from).collect()),
+        }
+    }
+
+    pub fn encode(self, text: &str) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for ch in text.chars() {
+            self.write_char(&mut bytes, ch)?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Reads from the process's stdin and writes to its stdout, in UTF-8 unless created with
+/// `with_encoding`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdIo {
+    encoding: Encoding,
+}
+
+impl StdIo {
+    pub fn with_encoding(encoding: Encoding) -> Self {
+        Self { encoding }
+    }
+}
+
+impl Io for StdIo {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        self.encoding.read_char(&mut stdin().lock())
+    }
+
+    fn write_char(&mut self, ch: char) -> io::Result<()> {
+        self.encoding.write_char(&mut stdout().lock(), ch)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        stdout().flush()
+    }
+}
+
+/// Reads from and writes to in-memory strings.
+///
+/// A `StringIo` created with `new` has all of its input available up front; once it has been
+/// consumed, reads return end of input. One created with `interactive` starts out empty and open:
+/// reading from it fails with `ErrorKind::WouldBlock` until input is pushed or the input is
+/// closed, which suspends a `Vm` with `Status::NeedsInput`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct StringIo {
+    input: VecDeque<char>,
+    input_open: bool,
+    output: String,
+}
+
+impl StringIo {
+    pub fn new(input: &str) -> Self {
+        Self {
+            input: input.chars().collect(),
+            input_open: false,
+            output: String::new(),
+        }
+    }
+
+    pub fn interactive() -> Self {
+        Self {
+            input_open: true,
+            ..Default::default()
+        }
+    }
+
+    /// Makes more input available to the program.
+    pub fn push_input(&mut self, input: &str) {
+        self.input.extend(input.chars());
+    }
+
+    /// Marks the end of input: once the pending input is consumed, reads return end of input.
+    pub fn close_input(&mut self) {
+        self.input_open = false;
+    }
+
+    /// Everything written so far.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    pub fn into_output(self) -> String {
+        self.output
+    }
+}
+
+impl Io for StringIo {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        match self.input.pop_front() {
+            None if self.input_open => Err(io::ErrorKind::WouldBlock.into()),
+            ch => Ok(ch),
+        }
+    }
+
+    fn write_char(&mut self, ch: char) -> io::Result<()> {
+        self.output.push(ch);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decodes input from any `Read` and encodes output to any `Write`, in UTF-8 unless created with
+/// `with_encoding`.
+///
+/// Using `&[u8]` and `Vec<u8>` gives an in-memory byte buffer backend.
+#[derive(Debug, Clone, Default)]
+pub struct ReadWriteIo<R: Read, W: Write> {
+    reader: R,
+    writer: W,
+    encoding: Encoding,
+}
+
+impl<R: Read, W: Write> ReadWriteIo<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self::with_encoding(reader, writer, Encoding::Utf8)
+    }
+
+    pub fn with_encoding(reader: R, writer: W, encoding: Encoding) -> Self {
+        Self {
+            reader,
+            writer,
+            encoding,
+        }
+    }
+
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R: Read, W: Write> Io for ReadWriteIo<R, W> {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        self.encoding.read_char(&mut self.reader)
+    }
+
+    fn write_char(&mut self, ch: char) -> io::Result<()> {
+        self.encoding.write_char(&mut self.writer, ch)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
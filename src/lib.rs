@@ -13,16 +13,27 @@
 // limitations under the License.
 
 use std::borrow::Borrow;
-use std::io::{stdin, Read};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::mem;
 use std::ops::Deref;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 use log::debug;
-use unicode_reader::CodePoints;
 
-use crate::parse::{parse_toplevel, Application, CharPosIterator, Combinator, SyntaxTree};
+pub use crate::error::Error;
+pub use crate::io::{Encoding, Io, ReadWriteIo, StdIo, StringIo};
+use crate::parse::{parse_chars, Application, Combinator, Span, SyntaxTree};
+use crate::unparse::combinator_to_string;
 
-mod parse;
+mod error;
+pub mod io;
+pub mod parse;
+pub mod unparse;
 
 /// All values in Unlambda are formally unary functions.
 ///
@@ -94,12 +105,158 @@ pub enum Expression {
     Application(Rc<Function>, Rc<Function>),
 }
 
+/// Values are rendered as Unlambda source that evaluates back to them.
+///
+/// Continuations cannot be written down and are rendered as `<continuation>`. Promises created by
+/// `d` in the program text point into the compiled code, which `Display` has no access to, so
+/// they are rendered as `<promise>`; use `to_unlambda` to write them out.
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.render(None))
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.render(None))
+    }
+}
+
+/// The code a value was produced by, needed to write out promises.
+#[derive(Clone, Copy)]
+struct Code<'a> {
+    opcodes: &'a [OpCode],
+    /// The values bound with `Vm::bind`, which `OpCode::PushValue` refers to.
+    values: &'a [Rc<Function>],
+}
+
+impl Function {
+    /// Renders the value as Unlambda source, writing out the delayed expression of promises from
+    /// `code`, which must be the code of the `Vm` that produced the value. Values bound with
+    /// `Vm::bind` are rendered as `<value>`; use `Vm::to_unlambda` to write them out too.
+    pub fn to_unlambda(&self, code: &[OpCode]) -> String {
+        self.render(Some(Code {
+            opcodes: code,
+            values: &[],
+        }))
+    }
+
+    fn render(&self, code: Option<Code>) -> String {
+        let mut out = String::new();
+        render_function(self, code, &mut out);
+        out
+    }
+}
+
+impl Expression {
+    /// Renders the delayed expression as Unlambda source, see `Function::to_unlambda`.
+    pub fn to_unlambda(&self, code: &[OpCode]) -> String {
+        self.render(Some(Code {
+            opcodes: code,
+            values: &[],
+        }))
+    }
+
+    fn render(&self, code: Option<Code>) -> String {
+        let mut out = String::new();
+        render_expression(self, code, &mut out);
+        out
+    }
+}
+
+fn render_function(fun: &Function, code: Option<Code>, out: &mut String) {
+    match fun {
+        Function::I => out.push('i'),
+        Function::K => out.push('k'),
+        Function::K1(x) => {
+            out.push_str("`k");
+            render_function(x, code, out);
+        }
+        Function::S => out.push('s'),
+        Function::S1(x) => {
+            out.push_str("`s");
+            render_function(x, code, out);
+        }
+        Function::S2(x, y) => {
+            out.push_str("``s");
+            render_function(x, code, out);
+            render_function(y, code, out);
+        }
+        Function::V => out.push('v'),
+        Function::D => out.push('d'),
+        Function::D1(e) => {
+            out.push_str("`d");
+            render_expression(e, code, out);
+        }
+        Function::C => out.push('c'),
+        Function::C1(_) => out.push_str("<continuation>"),
+        Function::E => out.push('e'),
+        Function::Read => out.push('@'),
+        Function::Reprint => out.push('|'),
+        Function::Compare(ch) => {
+            out.push('?');
+            out.push(*ch);
+        }
+        Function::Dot('\n') => out.push('r'),
+        Function::Dot(ch) => {
+            out.push('.');
+            out.push(*ch);
+        }
+    }
+}
+
+fn render_expression(expression: &Expression, code: Option<Code>, out: &mut String) {
+    match expression {
+        Expression::Promise(at) => match code {
+            Some(code) => decompile_promise(code, *at, out),
+            None => out.push_str("<promise>"),
+        },
+        Expression::Function(x) => render_function(x, code, out),
+        Expression::Application(x, y) => {
+            out.push('`');
+            render_function(x, code, out);
+            render_function(y, code, out);
+        }
+    }
+}
+
+/// Writes out the expression a promise would evaluate. This is the code between the promise's
+/// target and the `Invoke` its `CheckSuspend` jumps past, which is always the output of `compile`
+/// for a single syntax tree.
+fn decompile_promise(code: Code, at: usize, out: &mut String) {
+    let end = match code.opcodes[at - 1] {
+        OpCode::CheckSuspend(offset) => at - 2 + offset,
+        _ => panic!("promise does not point to a CheckSuspend opcode"),
+    };
+    let mut terms: Vec<String> = Vec::new();
+    for opcode in &code.opcodes[at..end] {
+        match opcode {
+            OpCode::PushImmediate(c) => terms.push(combinator_to_string(*c)),
+            OpCode::PushValue(i) => terms.push(match code.values.get(*i) {
+                Some(value) => value.render(Some(code)),
+                None => "<value>".to_string(),
+            }),
+            OpCode::CheckSuspend(_) => (),
+            OpCode::Invoke => {
+                let arg = terms.pop().unwrap();
+                let func = terms.pop().unwrap();
+                terms.push(format!("`{}{}", func, arg));
+            }
+            _ => panic!("unexpected {:?} in compiled expression", opcode),
+        }
+    }
+    debug_assert_eq!(terms.len(), 1);
+    out.push_str(&terms.pop().unwrap());
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
-enum OpCode {
+pub enum OpCode {
     /// Used during compilation phase to reserve a spot for an instruction that we don't know yet.
     Placeholder,
     /// Push the given combinator to the stack.
     PushImmediate(Combinator),
+    /// Push the value bound with `Vm::bind` at the given index to the stack.
+    PushValue(usize),
     /// Swap the two top values on the stack.
     Swap,
     /// Move the top stack value to third position, moving second and third to first and second,
@@ -146,7 +303,7 @@ const D1_APPLICATION_CODE: [OpCode; D1_APPLICATION_LEN] =
 ///
 /// Always use `push_rstack` to add elements to the return stack, as it performs TCO. The TCO
 /// invariant is that `stack[-1].to != stack[-2].from`.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct VmState {
     stack: Vec<Rc<Function>>,
     rstack: Vec<(usize, usize)>,
@@ -170,30 +327,391 @@ impl VmState {
     }
 }
 
-impl Default for VmState {
-    fn default() -> Self {
-        Self {
-            stack: Vec::new(),
-            rstack: Vec::new(),
-            pc: 0,
-            cur_char: None,
+/// Resource limits checked while a program runs. `None` means unlimited, which is the default.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RunLimits {
+    /// Maximum number of opcodes to execute.
+    pub max_steps: Option<u64>,
+    /// Maximum number of values on the value stack.
+    pub max_stack_depth: Option<usize>,
+    /// Maximum number of entries on the return stack.
+    pub max_return_depth: Option<usize>,
+    /// Maximum number of `Function` values to allocate.
+    pub max_allocations: Option<u64>,
+    /// Wall-clock time after which the program is stopped. The clock is only read every 1024
+    /// steps, so the program may slightly overrun it.
+    pub deadline: Option<Instant>,
+}
+
+/// The limit that stopped a program, see `RunLimits`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Limit {
+    Steps,
+    StackDepth,
+    ReturnDepth,
+    Allocations,
+    Deadline,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Steps => "step limit",
+            Limit::StackDepth => "stack depth limit",
+            Limit::ReturnDepth => "return stack depth limit",
+            Limit::Allocations => "allocation limit",
+            Limit::Deadline => "deadline",
+        })
+    }
+}
+
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// Resources used so far by a running program.
+#[derive(Debug, Clone, Copy, Default)]
+struct Counters {
+    steps: u64,
+    allocations: u64,
+}
+
+impl Counters {
+    fn alloc(&mut self, fun: Function) -> Rc<Function> {
+        self.allocations += 1;
+        Rc::new(fun)
+    }
+}
+
+impl RunLimits {
+    fn check(&self, vm_state: &VmState, counters: &Counters) -> Result<(), Error> {
+        let exceeded = |limit| {
+            Err(Error::LimitExceeded {
+                limit,
+                steps: counters.steps,
+                span: None,
+            })
+        };
+        if self.max_steps.is_some_and(|max| counters.steps >= max) {
+            return exceeded(Limit::Steps);
+        }
+        if self
+            .max_stack_depth
+            .is_some_and(|max| vm_state.stack.len() > max)
+        {
+            return exceeded(Limit::StackDepth);
+        }
+        if self
+            .max_return_depth
+            .is_some_and(|max| vm_state.rstack.len() > max)
+        {
+            return exceeded(Limit::ReturnDepth);
+        }
+        if self
+            .max_allocations
+            .is_some_and(|max| counters.allocations > max)
+        {
+            return exceeded(Limit::Allocations);
+        }
+        if counters.steps.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+            && self.deadline.is_some_and(|d| Instant::now() >= d)
+        {
+            return exceeded(Limit::Deadline);
+        }
+        Ok(())
+    }
+}
+
+/// A flag that stops a running `Vm` from elsewhere, such as another thread, a timer or a signal
+/// handler. Clones share the same flag, and any number of VMs can be given the same handle.
+///
+/// The VM checks the flag before every step. When it finds it set, it clears it and fails with
+/// `Error::Interrupted` without running the step, so it can be resumed afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the VMs using this handle to stop before their next step. This only stores to an
+    /// atomic, so it is safe to call from a signal handler.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether an interrupt is pending, that is, requested but not acted upon yet.
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+
+    /// Withdraws a pending interrupt.
+    pub fn reset(&self) {
+        self.interrupted.store(false, Ordering::SeqCst);
+    }
+
+    fn take(&self) -> bool {
+        // Checked on every step, so avoid the cost of a write in the common case.
+        self.interrupted.load(Ordering::Relaxed) && self.interrupted.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// What a `Vm` is doing after a call to `step`, `run_for` or `run`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Status {
+    /// The program has not terminated yet.
+    Running,
+    /// The program ran to completion and produced this value.
+    Finished(Rc<Function>),
+    /// The program invoked `e` with this value.
+    Exited(Rc<Function>),
+    /// The program is trying to read with `@`, but the `Io` reported that no input is available
+    /// yet by returning `ErrorKind::WouldBlock`. Make input available, then resume the VM to
+    /// retry the read.
+    NeedsInput,
+}
+
+/// Why a program stopped running, as returned by `run_to_termination`.
+#[derive(Debug)]
+pub enum Termination {
+    /// The program ran to completion and produced this value.
+    Finished(Rc<Function>),
+    /// The program invoked `e` with this value.
+    Exited(Rc<Function>),
+    /// The program went over one of its `RunLimits` after executing `steps` opcodes.
+    LimitExceeded {
+        limit: Limit,
+        steps: u64,
+        span: Option<Span>,
+    },
+    /// Reading input or writing output failed. Input that is not available yet counts as a
+    /// failure with `ErrorKind::WouldBlock`.
+    Io {
+        error: std::io::Error,
+        span: Option<Span>,
+    },
+    /// The program was stopped through its `InterruptHandle` after executing `steps` opcodes.
+    Interrupted { steps: u64, span: Option<Span> },
+}
+
+impl Termination {
+    /// The value the program stopped with, or the error it failed with.
+    pub fn into_result(self) -> Result<Rc<Function>, Error> {
+        match self {
+            Termination::Finished(v) | Termination::Exited(v) => Ok(v),
+            Termination::LimitExceeded { limit, steps, span } => {
+                Err(Error::LimitExceeded { limit, steps, span })
+            }
+            Termination::Io { error, span } => Err(Error::Io { error, span }),
+            Termination::Interrupted { steps, span } => Err(Error::Interrupted { steps, span }),
         }
     }
 }
 
-fn run_vm(code: &[OpCode], entry_point: usize) -> Result<Rc<Function>, String> {
-    let mut vm_state = VmState::default();
-    vm_state.pc = entry_point;
+/// A compiled program together with its execution state.
+///
+/// The program only advances when the host asks it to, so execution can be paused after any
+/// number of steps, or while it waits for input, and resumed later. Once the program has
+/// terminated, further calls keep returning the same status.
+///
+/// Limit errors are raised before the offending step runs, so the VM can be resumed after
+/// raising its limits, and so are interrupts. When reading input or writing output fails, the
+/// application that needed it is left to run again, so the next step retries it.
+#[derive(Debug, Clone)]
+pub struct Vm {
+    code: Vec<OpCode>,
+    spans: Vec<Option<Span>>,
+    /// Values bound with `bind`, in the order they were bound.
+    values: Vec<Rc<Function>>,
+    /// The index in `values` of the value each name is currently bound to.
+    bindings: HashMap<String, usize>,
+    state: VmState,
+    limits: RunLimits,
+    interrupt: InterruptHandle,
+    counters: Counters,
+    status: Option<Status>,
+}
+
+impl Vm {
+    /// Parses and compiles a program, ready to execute its first instruction.
+    pub fn new(code: &str) -> Result<Self, Error> {
+        Self::from_syntax_tree(&parse_chars(code.chars())?)
+    }
+
+    /// Compiles an already parsed program. Fails with `Error::Syntax` if the tree contains errors,
+    /// and with `Error::UnboundReference` if it contains references.
+    pub fn from_syntax_tree(st: &SyntaxTree) -> Result<Self, Error> {
+        let mut vm = Self {
+            code: Vec::new(),
+            spans: Vec::new(),
+            values: Vec::new(),
+            bindings: HashMap::new(),
+            state: VmState::default(),
+            limits: RunLimits::default(),
+            interrupt: InterruptHandle::new(),
+            counters: Counters::default(),
+            status: None,
+        };
+        for &opcode in S2_CODE
+            .iter()
+            .chain(&D1_PROMISE_CODE)
+            .chain(&D1_APPLICATION_CODE)
+        {
+            vm.code.push(opcode);
+            vm.spans.push(None);
+        }
+        vm.load(st)?;
+        Ok(vm)
+    }
+
+    /// Compiles another program after the current one, discarding the current execution state,
+    /// and sets the VM up to run it. References in the program are compiled to the values they are
+    /// bound to with `bind`. Values produced by earlier programs stay valid, so they can be bound
+    /// and used by later ones, even promises and continuations. If compiling fails, the VM is left
+    /// as it was.
+    pub fn load(&mut self, st: &SyntaxTree) -> Result<(), Error> {
+        let mut program = Program {
+            code: mem::take(&mut self.code),
+            spans: mem::take(&mut self.spans),
+        };
+        let entry_point = program.code.len();
+        let compiled = compile(st, &mut program, &self.bindings);
+        match compiled {
+            Ok(()) => program.push(OpCode::Finish, None),
+            Err(_) => {
+                program.code.truncate(entry_point);
+                program.spans.truncate(entry_point);
+            }
+        }
+        self.code = program.code;
+        self.spans = program.spans;
+        compiled?;
+        debug!(
+            "Compiled: {:?}",
+            self.code[entry_point..]
+                .iter()
+                .enumerate()
+                .collect::<Vec<_>>()
+        );
+
+        // The loop expects a top element on the return stack in order to check for auto-returns.
+        // Add a sentinel here that will never trigger, and would jump to an illegal location if
+        // it did.
+        self.state = VmState {
+            stack: Vec::new(),
+            rstack: vec![(self.code.len(), self.code.len())],
+            pc: entry_point,
+            cur_char: self.state.cur_char,
+        };
+        self.status = None;
+        Ok(())
+    }
+
+    /// Binds a name to a value, so that references to the name in programs loaded afterwards
+    /// evaluate to the value. Programs loaded before keep the value the name was bound to then.
+    pub fn bind(&mut self, name: &str, value: Rc<Function>) {
+        self.bindings.insert(name.to_string(), self.values.len());
+        self.values.push(value);
+    }
+
+    /// Whether a name is bound with `bind`.
+    pub fn is_bound(&self, name: &str) -> bool {
+        self.bindings.contains_key(name)
+    }
+
+    /// Renders a value produced by this VM as Unlambda source, see `Function::to_unlambda`.
+    pub fn to_unlambda(&self, value: &Function) -> String {
+        value.render(Some(Code {
+            opcodes: &self.code,
+            values: &self.values,
+        }))
+    }
+
+    /// Sets the limits checked before every subsequent step.
+    pub fn set_limits(&mut self, limits: RunLimits) {
+        self.limits = limits;
+    }
+
+    /// The handle that stops this VM. Each VM gets a handle of its own when it is created.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Makes this VM stop when `handle` is interrupted, instead of its current handle.
+    pub fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.interrupt = handle;
+    }
+
+    /// Number of opcodes executed so far.
+    pub fn steps(&self) -> u64 {
+        self.counters.steps
+    }
 
-    // The loop expects a top element on the return stack in order to check for auto-returns.
-    // Add a sentinel here that will never trigger, and would jump to an illegal location if it did.
-    vm_state.rstack.push((code.len(), code.len()));
+    /// The compiled program.
+    pub fn code(&self) -> &[OpCode] {
+        &self.code
+    }
 
-    loop {
+    /// Address of the next opcode to execute.
+    pub fn pc(&self) -> usize {
+        self.state.pc
+    }
+
+    /// The source span the opcode at `pc` was compiled from, or `None` for microcode.
+    pub fn span_at(&self, pc: usize) -> Option<Span> {
+        self.spans.get(pc).copied().flatten()
+    }
+
+    /// The span of every opcode in `code`, indexed by address.
+    pub fn source_map(&self) -> &[Option<Span>] {
+        &self.spans
+    }
+
+    /// The value stack, with the top of the stack last.
+    pub fn stack(&self) -> &[Rc<Function>] {
+        &self.state.stack
+    }
+
+    /// The return stack as `(to, from)` pairs, innermost last: once the program counter reaches
+    /// `from`, execution continues at `to`.
+    pub fn return_stack(&self) -> &[(usize, usize)] {
+        // Skip the sentinel.
+        &self.state.rstack[1..]
+    }
+
+    /// The status the program terminated with, if it has.
+    pub fn status(&self) -> Option<&Status> {
+        self.status.as_ref()
+    }
+
+    /// Executes a single opcode.
+    pub fn step(&mut self, io: &mut dyn Io) -> Result<Status, Error> {
+        if let Some(status) = &self.status {
+            return Ok(status.clone());
+        }
+        let pc = self.state.pc;
+        self.execute(io).map_err(|e| e.at(self.spans[pc]))
+    }
+
+    fn execute(&mut self, io: &mut dyn Io) -> Result<Status, Error> {
+        let (code, vm_state, counters) = (&self.code, &mut self.state, &mut self.counters);
+        let values = &self.values;
+        if self.interrupt.take() {
+            return Err(Error::Interrupted {
+                steps: counters.steps,
+                span: None,
+            });
+        }
+        self.limits.check(vm_state, counters)?;
+        counters.steps += 1;
         let opcode = code[vm_state.pc];
         match opcode {
             OpCode::Placeholder => panic!("placeholder not replaced during compilation"),
-            OpCode::PushImmediate(c) => vm_state.stack.push(Rc::new(Function::from_combinator(c))),
+            OpCode::PushImmediate(c) => vm_state
+                .stack
+                .push(counters.alloc(Function::from_combinator(c))),
+            OpCode::PushValue(i) => vm_state.stack.push(values[i].clone()),
             OpCode::Rot => {
                 let (fst, snd, thr) = (
                     vm_state.stack.pop().unwrap(),
@@ -214,7 +732,7 @@ fn run_vm(code: &[OpCode], entry_point: usize) -> Result<Rc<Function>, String> {
                     vm_state.stack.pop().unwrap();
                     vm_state
                         .stack
-                        .push(Rc::new(Function::D1(Expression::Promise(vm_state.pc + 1))));
+                        .push(counters.alloc(Function::D1(Expression::Promise(vm_state.pc + 1))));
                     vm_state.pc += offset;
                 } else {
                     vm_state.pc += 1;
@@ -230,7 +748,7 @@ fn run_vm(code: &[OpCode], entry_point: usize) -> Result<Rc<Function>, String> {
                     let operand_operator = vm_state.stack.pop().unwrap();
                     vm_state
                         .stack
-                        .push(Rc::new(Function::D1(Expression::Application(
+                        .push(counters.alloc(Function::D1(Expression::Application(
                             operand_operator.clone(),
                             operand_operand.clone(),
                         ))));
@@ -240,22 +758,34 @@ fn run_vm(code: &[OpCode], entry_point: usize) -> Result<Rc<Function>, String> {
                 }
             }
             OpCode::Invoke => {
-                if let Some(ret) = invoke(code, &mut vm_state)? {
-                    return Ok(ret);
+                match invoke(code, vm_state, io, counters)? {
+                    None => (),
+                    Some(Status::NeedsInput) => {
+                        // Nothing was executed, so don't count this step.
+                        counters.steps -= 1;
+                        return Ok(Status::NeedsInput);
+                    }
+                    Some(status) => return Ok(self.stop(status)),
                 }
             }
             OpCode::Finish => {
-                // The rstack should contain only our sentinel return point
+                // The rstack should contain only a sentinel return point, which may be from before
+                // a call to `apply` if a continuation from an earlier run was invoked.
                 debug_assert_eq!(vm_state.stack.len(), 1);
-                debug_assert_eq!(vm_state.rstack, [(code.len(), code.len())]);
-                return Ok(vm_state.stack.pop().unwrap());
+                debug_assert_eq!(vm_state.rstack.len(), 1);
+                io.flush()?;
+                let ret = vm_state.stack.pop().unwrap();
+                return Ok(self.stop(Status::Finished(ret)));
             }
         }
         match opcode {
             OpCode::Invoke | OpCode::CheckSuspend(_) | OpCode::CheckDynamicSuspend(_) => (),
             _ => vm_state.pc += 1,
         }
-        debug!("{:?} ({:?} → {:?})", &vm_state, opcode, code[vm_state.pc]);
+        debug!(
+            "{:?} ({:?} → {:?} at {:?})",
+            vm_state, opcode, code[vm_state.pc], self.spans[vm_state.pc]
+        );
 
         let (to, from) = *vm_state.rstack.last().unwrap();
         if vm_state.pc == from {
@@ -263,17 +793,90 @@ fn run_vm(code: &[OpCode], entry_point: usize) -> Result<Rc<Function>, String> {
             vm_state.pc = to;
             vm_state.rstack.pop();
         }
+
+        Ok(Status::Running)
+    }
+
+    /// Executes up to `steps` opcodes, stopping early if the program terminates or needs input.
+    pub fn run_for(&mut self, steps: u64, io: &mut dyn Io) -> Result<Status, Error> {
+        for _ in 0..steps {
+            match self.step(io)? {
+                Status::Running => (),
+                status => return Ok(status),
+            }
+        }
+        Ok(Status::Running)
+    }
+
+    /// Runs the program until it terminates or needs input.
+    pub fn run(&mut self, io: &mut dyn Io) -> Result<Status, Error> {
+        loop {
+            match self.step(io)? {
+                Status::Running => (),
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// Discards the current execution state and sets the VM up to apply `func` to `arg`, which is
+    /// then run like a new program. This lets values produced by the program be used further,
+    /// even promises and continuations, which only make sense along with the code that created
+    /// them.
+    pub fn apply(&mut self, func: Rc<Function>, arg: Rc<Function>) {
+        let pc = self.code.len();
+        for &opcode in &[OpCode::Invoke, OpCode::Finish] {
+            self.code.push(opcode);
+            self.spans.push(None);
+        }
+        self.state = VmState {
+            stack: vec![func, arg],
+            rstack: vec![(self.code.len(), self.code.len())],
+            pc,
+            cur_char: self.state.cur_char,
+        };
+        self.status = None;
+    }
+
+    /// Runs the program until it terminates, reporting runtime errors as part of the result.
+    pub fn run_to_termination(&mut self, io: &mut dyn Io) -> Termination {
+        match self.run(io) {
+            Ok(Status::Finished(v)) => Termination::Finished(v),
+            Ok(Status::Exited(v)) => Termination::Exited(v),
+            Ok(Status::NeedsInput) => Termination::Io {
+                error: ErrorKind::WouldBlock.into(),
+                span: self.span_at(self.pc()),
+            },
+            Ok(Status::Running) => unreachable!("run returned before the program terminated"),
+            Err(Error::LimitExceeded { limit, steps, span }) => {
+                Termination::LimitExceeded { limit, steps, span }
+            }
+            Err(Error::Io { error, span }) => Termination::Io { error, span },
+            Err(Error::Interrupted { steps, span }) => Termination::Interrupted { steps, span },
+            Err(e) => unreachable!("compile-time error {:?} while running", e),
+        }
+    }
+
+    fn stop(&mut self, status: Status) -> Status {
+        self.status = Some(status.clone());
+        status
     }
 }
 
-fn invoke(code: &[OpCode], vm_state: &mut VmState) -> Result<Option<Rc<Function>>, String> {
+fn invoke(
+    code: &[OpCode],
+    vm_state: &mut VmState,
+    io: &mut dyn Io,
+    counters: &mut Counters,
+) -> Result<Option<Status>, Error> {
     let (arg, fun) = (vm_state.stack.pop().unwrap(), vm_state.stack.pop().unwrap());
     match fun.borrow() {
         Function::I => vm_state.stack.push(arg),
-        Function::K => vm_state.stack.push(Rc::new(Function::K1(arg))),
+        Function::K => vm_state.stack.push(counters.alloc(Function::K1(arg))),
         Function::K1(val) => vm_state.stack.push(val.clone()),
-        Function::S => vm_state.stack.push(Rc::new(Function::S1(arg))),
-        Function::S1(val) => vm_state.stack.push(Rc::new(Function::S2(val.clone(), arg))),
+        Function::S => vm_state.stack.push(counters.alloc(Function::S1(arg))),
+        Function::S1(val) => vm_state
+            .stack
+            .push(counters.alloc(Function::S2(val.clone(), arg))),
         Function::S2(val1, val2) => {
             // We want to compute ``(val1)(arg)`(val2)(arg), evaluating `(val1)(arg) first.
             // Push the necessary values on the stack, and hand it off to the S2 microcode.
@@ -287,7 +890,7 @@ fn invoke(code: &[OpCode], vm_state: &mut VmState) -> Result<Option<Rc<Function>
         Function::V => vm_state.stack.push(fun.clone()),
         Function::D => vm_state
             .stack
-            .push(Rc::new(Function::D1(Expression::Function(arg)))),
+            .push(counters.alloc(Function::D1(Expression::Function(arg)))),
         Function::D1(Expression::Promise(at)) => {
             // The promise object points to a location in the code which contains the necessary
             // instructions to force the promise. The instructions in question end just before
@@ -318,7 +921,7 @@ fn invoke(code: &[OpCode], vm_state: &mut VmState) -> Result<Option<Rc<Function>
             vm_state.stack.push(arg);
             vm_state
                 .stack
-                .push(Rc::new(Function::C1(Box::new(saved_state))));
+                .push(counters.alloc(Function::C1(Box::new(saved_state))));
         }
         Function::C1(cont) => {
             vm_state.stack = cont.stack.clone();
@@ -326,14 +929,32 @@ fn invoke(code: &[OpCode], vm_state: &mut VmState) -> Result<Option<Rc<Function>
             vm_state.rstack = cont.rstack.clone();
             vm_state.pc = cont.pc;
         }
-        Function::E => return Ok(Some(arg)),
+        Function::E => {
+            if let Err(e) = io.flush() {
+                // As for `@`, so that exiting is retried along with the flush.
+                vm_state.stack.push(fun);
+                vm_state.stack.push(arg);
+                return Err(e.into());
+            }
+            return Ok(Some(Status::Exited(arg)));
+        }
         Function::Read => {
-            let ch = CodePoints::from(stdin().bytes())
-                .next()
-                .and_then(|v| v.ok());
+            let ch = match io.flush().and_then(|()| io.read_char()) {
+                Ok(ch) => ch,
+                Err(e) => {
+                    // Put everything back so the same Invoke runs again once input is available,
+                    // or once the caller has dealt with the error.
+                    vm_state.stack.push(fun);
+                    vm_state.stack.push(arg);
+                    return match e.kind() {
+                        ErrorKind::WouldBlock => Ok(Some(Status::NeedsInput)),
+                        _ => Err(e.into()),
+                    };
+                }
+            };
             vm_state.cur_char = ch;
             vm_state.stack.push(arg);
-            vm_state.stack.push(Rc::new(if ch.is_some() {
+            vm_state.stack.push(counters.alloc(if ch.is_some() {
                 Function::I
             } else {
                 Function::V
@@ -342,17 +963,22 @@ fn invoke(code: &[OpCode], vm_state: &mut VmState) -> Result<Option<Rc<Function>
         Function::Reprint => {
             let fun = vm_state.cur_char.map_or(Function::V, Function::Dot);
             vm_state.stack.push(arg);
-            vm_state.stack.push(Rc::new(fun));
+            vm_state.stack.push(counters.alloc(fun));
         }
         Function::Compare(ch) => {
-            let is_same = vm_state.cur_char.map_or(false, |c| c == *ch);
+            let is_same = vm_state.cur_char == Some(*ch);
             vm_state.stack.push(arg);
             vm_state
                 .stack
-                .push(Rc::new(if is_same { Function::I } else { Function::V }));
+                .push(counters.alloc(if is_same { Function::I } else { Function::V }));
         }
         Function::Dot(ch) => {
-            print!("{}", ch);
+            if let Err(e) = io.write_char(*ch) {
+                // As for `@`, so that the write is retried.
+                vm_state.stack.push(fun);
+                vm_state.stack.push(arg);
+                return Err(e.into());
+            }
             vm_state.stack.push(arg);
         }
     }
@@ -376,38 +1002,152 @@ fn invoke(code: &[OpCode], vm_state: &mut VmState) -> Result<Option<Rc<Function>
     Ok(None)
 }
 
-fn compile(st: &SyntaxTree, code: &mut Vec<OpCode>) -> Result<(), String> {
-    match st {
-        SyntaxTree::Combinator(c) => code.push(OpCode::PushImmediate(*c)),
-        SyntaxTree::Application(Application { func, arg }) => {
-            compile(func, code)?;
-            let placeholder_position = code.len();
-            code.push(OpCode::Placeholder);
-            compile(arg, code)?;
-            code.push(OpCode::Invoke);
-            let next_position = code.len();
-            code[placeholder_position] = OpCode::CheckSuspend(next_position - placeholder_position);
+/// Bytecode, along with the source span each instruction was compiled from. Microcode and the
+/// final `Finish` have no span.
+#[derive(Debug, Clone, Default)]
+struct Program {
+    code: Vec<OpCode>,
+    spans: Vec<Option<Span>>,
+}
+
+impl Program {
+    fn push(&mut self, opcode: OpCode, span: Option<Span>) {
+        self.code.push(opcode);
+        self.spans.push(span);
+    }
+}
+
+/// What is left to do to compile a tree. An application is compiled as its function, then a
+/// `CheckSuspend` that skips over its argument, then its argument and an `Invoke`.
+enum CompileStep<'a> {
+    Tree(&'a SyntaxTree),
+    Suspend(Span),
+    Invoke(Span),
+}
+
+fn compile(
+    st: &SyntaxTree,
+    program: &mut Program,
+    bindings: &HashMap<String, usize>,
+) -> Result<(), Error> {
+    // Work is kept on explicit stacks rather than the Rust stack, so that nesting can go
+    // arbitrarily deep.
+    let mut steps = vec![CompileStep::Tree(st)];
+    let mut placeholder_positions = Vec::new();
+    while let Some(step) = steps.pop() {
+        match step {
+            CompileStep::Tree(SyntaxTree::Combinator(c, span)) => {
+                program.push(OpCode::PushImmediate(*c), Some(*span))
+            }
+            CompileStep::Tree(SyntaxTree::Application(Application { func, arg, span })) => {
+                steps.push(CompileStep::Invoke(*span));
+                steps.push(CompileStep::Tree(arg));
+                steps.push(CompileStep::Suspend(*span));
+                steps.push(CompileStep::Tree(func));
+            }
+            CompileStep::Tree(SyntaxTree::Error(span)) => {
+                return Err(Error::Syntax { span: *span })
+            }
+            CompileStep::Tree(SyntaxTree::Reference(name, span)) => match bindings.get(name) {
+                Some(&i) => program.push(OpCode::PushValue(i), Some(*span)),
+                None => {
+                    return Err(Error::UnboundReference {
+                        name: name.clone(),
+                        span: *span,
+                    })
+                }
+            },
+            CompileStep::Suspend(span) => {
+                placeholder_positions.push(program.code.len());
+                program.push(OpCode::Placeholder, Some(span));
+            }
+            CompileStep::Invoke(span) => {
+                program.push(OpCode::Invoke, Some(span));
+                let placeholder_position = placeholder_positions.pop().unwrap();
+                let next_position = program.code.len();
+                program.code[placeholder_position] =
+                    OpCode::CheckSuspend(next_position - placeholder_position);
+            }
         }
     }
     Ok(())
 }
 
-fn compile_toplevel(st: &SyntaxTree) -> Result<(Vec<OpCode>, usize), String> {
-    let mut code = S2_CODE.to_vec();
-    code.extend_from_slice(&D1_PROMISE_CODE);
-    code.extend_from_slice(&D1_APPLICATION_CODE);
-    let entry_point = code.len();
-    compile(st, &mut code)?;
-    code.push(OpCode::Finish);
-    debug!(
-        "Compiled: {:?}",
-        code.iter().enumerate().collect::<Vec<_>>()
-    );
-    Ok((code, entry_point))
-}
-
-pub fn parse_compile_run(code: &str) -> Result<Function, String> {
-    let st = parse_toplevel(&mut CharPosIterator::new(code.chars()).peekable())?;
-    let (code, entry_point) = compile_toplevel(&st)?;
-    run_vm(&code, entry_point).map(|v| (*v).clone())
+/// Runs a program against the process's stdin and stdout.
+pub fn parse_compile_run(code: &str) -> Result<Function, Error> {
+    parse_compile_run_with_io(code, &mut StdIo::default())
+}
+
+/// Runs a program, sending all of its input and output through `io`.
+pub fn parse_compile_run_with_io(code: &str, io: &mut dyn Io) -> Result<Function, Error> {
+    parse_compile_run_with_limits(code, io, &RunLimits::default())
+}
+
+/// Runs a program with input and output through `io`, stopping with `Error::LimitExceeded` if it
+/// goes over any of `limits`.
+pub fn parse_compile_run_with_limits(
+    code: &str,
+    io: &mut dyn Io,
+    limits: &RunLimits,
+) -> Result<Function, Error> {
+    let mut vm = Vm::new(code)?;
+    vm.set_limits(*limits);
+    match vm.run(io)? {
+        Status::Finished(v) | Status::Exited(v) => Ok((*v).clone()),
+        Status::NeedsInput => Err(std::io::Error::from(ErrorKind::WouldBlock).into()),
+        Status::Running => unreachable!("run returned before the program terminated"),
+    }
+}
+
+/// Runs a program with input and output through `io`, reporting why it stopped. Only errors found
+/// before the program starts, such as syntax errors, are returned as `Err`.
+pub fn run_to_termination(
+    code: &str,
+    io: &mut dyn Io,
+    limits: &RunLimits,
+) -> Result<Termination, Error> {
+    let mut vm = Vm::new(code)?;
+    vm.set_limits(*limits);
+    Ok(vm.run_to_termination(io))
+}
+
+/// What a program did when run by `run_capture`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Outcome {
+    /// The value the program evaluated to, or the argument `e` was applied to.
+    pub value: Function,
+    /// Everything the program printed.
+    pub stdout: String,
+    /// Whether the program stopped by applying `e`.
+    pub exited_via_e: bool,
+    /// Number of opcodes executed.
+    pub steps: u64,
+}
+
+/// Runs a program on the given input, capturing its output instead of printing it.
+pub fn run_capture(code: &str, input: &str) -> Result<Outcome, Error> {
+    run_capture_with_limits(code, input, &RunLimits::default())
+}
+
+/// Like `run_capture`, but stops with `Error::LimitExceeded` if the program goes over any of
+/// `limits`.
+pub fn run_capture_with_limits(
+    code: &str,
+    input: &str,
+    limits: &RunLimits,
+) -> Result<Outcome, Error> {
+    let mut vm = Vm::new(code)?;
+    vm.set_limits(*limits);
+    let mut io = StringIo::new(input);
+    let (value, exited_via_e) = match vm.run(&mut io)? {
+        Status::Finished(v) => (v, false),
+        Status::Exited(v) => (v, true),
+        status => unreachable!("run returned {:?} with all input available", status),
+    };
+    Ok(Outcome {
+        value: (*value).clone(),
+        stdout: io.into_output(),
+        exited_via_e,
+        steps: vm.steps(),
+    })
 }
@@ -14,9 +14,20 @@
 
 //! parse.rs - Parsing utilities
 //! This file just reads an Unlambda program into a syntax tree, printing errors and their
-//! positions if there are any.
+//! positions if there are any. `parse_cst` also keeps the comments and whitespace, for tools that
+//! need to write the source back out.
+//!
+//! Besides Unlambda proper, the parser accepts references of the form `$name`, which stand for
+//! terms defined elsewhere, such as in a REPL session. They only compile if the name is bound to
+//! a value with `Vm::bind`.
 
-use std::iter::Peekable;
+use std::fmt;
+use std::io::{BufReader, Read};
+use std::iter::{self, Peekable};
+use std::mem;
+
+use crate::error::Error;
+use crate::io::Encoding;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Combinator {
@@ -33,16 +44,77 @@ pub enum Combinator {
     Dot(char),
 }
 
+/// The region of source text a term was parsed from. `start` is the position of its first
+/// character (the backquote, for applications) and `end` the position of its last one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Span {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+}
+
+impl Span {
+    fn at(position: (usize, usize)) -> Self {
+        Self {
+            start: position,
+            end: position,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Application {
     pub func: Box<SyntaxTree>,
     pub arg: Box<SyntaxTree>,
+    pub span: Span,
+}
+
+impl Drop for Application {
+    // The derived drop glue recurses once per level of nesting, which overflows the stack on
+    // deeply nested programs. Nested applications are moved out and dropped one at a time instead.
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        take_applications(self, &mut pending);
+        while let Some(mut st) = pending.pop() {
+            if let SyntaxTree::Application(app) = &mut st {
+                take_applications(app, &mut pending);
+            }
+        }
+    }
+}
+
+/// Moves the operands of `app` that are themselves applications to `into`, leaving error nodes in
+/// their place.
+fn take_applications(app: &mut Application, into: &mut Vec<SyntaxTree>) {
+    let span = app.span;
+    let mut take = |operand: &mut SyntaxTree| {
+        if let SyntaxTree::Application(_) = operand {
+            into.push(mem::replace(operand, SyntaxTree::Error(span)));
+        }
+    };
+    take(&mut app.func);
+    take(&mut app.arg);
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum SyntaxTree {
-    Combinator(Combinator),
+    Combinator(Combinator, Span),
     Application(Application),
+    /// A term that could not be parsed, left in its place by `parse_recovering`. The span covers
+    /// the offending character, or the token that was still waiting for a term when the input
+    /// ended. Trees containing errors cannot be compiled.
+    Error(Span),
+    /// A `$name` reference, without the `$`.
+    Reference(String, Span),
+}
+
+impl SyntaxTree {
+    pub fn span(&self) -> Span {
+        match self {
+            SyntaxTree::Combinator(_, span) => *span,
+            SyntaxTree::Application(app) => app.span,
+            SyntaxTree::Error(span) | SyntaxTree::Reference(_, span) => *span,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -71,57 +143,550 @@ fn consume_whitespace<I: Iterator<Item = CharPos>>(iterator: &mut Peekable<I>) {
     }
 }
 
-fn parse<I: Iterator<Item = CharPos>>(iterator: &mut Peekable<I>) -> Result<SyntaxTree, String> {
-    consume_whitespace(iterator);
-    let token = iterator
-        .next()
-        .ok_or_else(|| "unexpected EOF".to_string())?;
-    match token.item.to_ascii_lowercase() {
-        'k' => Ok(SyntaxTree::Combinator(Combinator::K)),
-        's' => Ok(SyntaxTree::Combinator(Combinator::S)),
-        'i' => Ok(SyntaxTree::Combinator(Combinator::I)),
-        'v' => Ok(SyntaxTree::Combinator(Combinator::V)),
-        'd' => Ok(SyntaxTree::Combinator(Combinator::D)),
-        'c' => Ok(SyntaxTree::Combinator(Combinator::C)),
-        'e' => Ok(SyntaxTree::Combinator(Combinator::E)),
-        '@' => Ok(SyntaxTree::Combinator(Combinator::Read)),
-        '|' => Ok(SyntaxTree::Combinator(Combinator::Reprint)),
-        '?' => iterator
-            .next()
-            .map(|c| SyntaxTree::Combinator(Combinator::Compare(c.item)))
-            .ok_or_else(|| format!("unexpected EOF after `.` at {:?}", token.position)),
-        '.' => iterator
+fn eof_after(token: CharPos) -> Error {
+    Error::UnexpectedEof {
+        after: Some(token.item),
+        position: Some(token.position),
+    }
+}
+
+/// Whether a character can be part of the name in a `$name` reference.
+pub fn is_name_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+/// The first token of a term, with whatever characters belong to it already read: a complete
+/// combinator along with the character it was spelled with, a reference, or the backquote of an
+/// application.
+enum Token {
+    Combinator(Combinator, Span, char),
+    Reference(String, Span),
+    Application(CharPos),
+}
+
+fn next_token<I: Iterator<Item = CharPos>>(iterator: &mut Peekable<I>) -> Result<Token, Error> {
+    let token = iterator.next().ok_or(Error::UnexpectedEof {
+        after: None,
+        position: None,
+    })?;
+    let combinator = |c| Ok(Token::Combinator(c, Span::at(token.position), token.item));
+    let with_char = |iterator: &mut Peekable<I>, make: fn(char) -> Combinator| {
+        iterator
             .next()
-            .map(|c| SyntaxTree::Combinator(Combinator::Dot(c.item)))
-            .ok_or_else(|| format!("unexpected EOF after `.` at {:?}", token.position)),
-        'r' => Ok(SyntaxTree::Combinator(Combinator::Dot('\n'))),
-        '[' | '`' => parse(iterator).and_then(|func| {
-            parse(iterator).map(|arg| {
-                SyntaxTree::Application(Application {
-                    func: Box::new(func),
-                    arg: Box::new(arg),
-                })
+            .map(|c| {
+                let span = Span {
+                    start: token.position,
+                    end: c.position,
+                };
+                Token::Combinator(make(c.item), span, token.item)
             })
+            .ok_or_else(|| eof_after(token))
+    };
+    match token.item.to_ascii_lowercase() {
+        'k' => combinator(Combinator::K),
+        's' => combinator(Combinator::S),
+        'i' => combinator(Combinator::I),
+        'v' => combinator(Combinator::V),
+        'd' => combinator(Combinator::D),
+        'c' => combinator(Combinator::C),
+        'e' => combinator(Combinator::E),
+        '@' => combinator(Combinator::Read),
+        '|' => combinator(Combinator::Reprint),
+        '?' => with_char(iterator, Combinator::Compare),
+        '.' => with_char(iterator, Combinator::Dot),
+        'r' => combinator(Combinator::Dot('\n')),
+        '[' | '`' => Ok(Token::Application(token)),
+        '$' => {
+            let mut name = String::new();
+            let mut end = token.position;
+            while let Some(c) = iterator.next_if(|c| is_name_char(c.item)) {
+                name.push(c.item);
+                end = c.position;
+            }
+            match iterator.peek() {
+                _ if !name.is_empty() => Ok(Token::Reference(
+                    name,
+                    Span {
+                        start: token.position,
+                        end,
+                    },
+                )),
+                Some(c) => Err(Error::UnexpectedToken {
+                    ch: c.item,
+                    position: c.position,
+                }),
+                None => Err(eof_after(token)),
+            }
+        }
+        _ => Err(Error::UnexpectedToken {
+            ch: token.item,
+            position: token.position,
         }),
-        c => Err(format!("unexpected token `{}` at {:?}", c, token.position)),
+    }
+}
+
+/// Blames the innermost application that is missing an operand.
+fn blame_application(token: CharPos) -> impl Fn(Error) -> Error {
+    move |e| match e {
+        Error::UnexpectedEof { after: None, .. } => eof_after(token),
+        e => e,
+    }
+}
+
+fn parse<I: Iterator<Item = CharPos>>(iterator: &mut Peekable<I>) -> Result<SyntaxTree, Error> {
+    // Applications still waiting for operands, innermost last, along with their function once it
+    // has been parsed. Keeping them here rather than on the Rust stack lets nesting go arbitrarily
+    // deep.
+    let mut pending: Vec<(CharPos, Option<SyntaxTree>)> = Vec::new();
+    loop {
+        consume_whitespace(iterator);
+        let token = next_token(iterator).map_err(|e| match pending.last() {
+            Some(&(token, _)) => blame_application(token)(e),
+            None => e,
+        })?;
+        let term = match token {
+            Token::Combinator(c, span, _) => SyntaxTree::Combinator(c, span),
+            Token::Reference(name, span) => SyntaxTree::Reference(name, span),
+            Token::Application(token) => {
+                pending.push((token, None));
+                continue;
+            }
+        };
+        if let Some(st) = complete(&mut pending, term) {
+            return Ok(st);
+        }
+    }
+}
+
+/// Hands a term to the innermost pending application, completing as many as it takes. Returns
+/// the whole tree once there are no applications left waiting.
+fn complete(
+    pending: &mut Vec<(CharPos, Option<SyntaxTree>)>,
+    mut term: SyntaxTree,
+) -> Option<SyntaxTree> {
+    loop {
+        match pending.last_mut() {
+            None => return Some(term),
+            Some((_, func @ None)) => {
+                *func = Some(term);
+                return None;
+            }
+            Some((_, Some(_))) => {
+                let (token, func) = pending.pop().unwrap();
+                let span = Span {
+                    start: token.position,
+                    end: term.span().end,
+                };
+                term = SyntaxTree::Application(Application {
+                    func: Box::new(func.unwrap()),
+                    arg: Box::new(term),
+                    span,
+                });
+            }
+        }
     }
 }
 
 pub fn parse_toplevel<I: Iterator<Item = CharPos>>(
     iterator: &mut Peekable<I>,
-) -> Result<SyntaxTree, String> {
+) -> Result<SyntaxTree, Error> {
     let res = parse(iterator)?;
     consume_whitespace(iterator);
     if let Some(cp) = iterator.next() {
-        Err(format!(
-            "unexpected character `{}` at {:?}",
-            cp.item, cp.position
-        ))
+        Err(Error::TrailingInput {
+            ch: cp.item,
+            position: cp.position,
+        })
     } else {
         Ok(res)
     }
 }
 
+/// Parses a whole program from any source of characters.
+pub fn parse_chars<I: Iterator<Item = char>>(chars: I) -> Result<SyntaxTree, Error> {
+    parse_toplevel(&mut CharPosIterator::new(chars).peekable())
+}
+
+/// Parses a whole program from UTF-8 text, reading it as parsing goes rather than all at once.
+pub fn parse_reader<R: Read>(reader: R) -> Result<SyntaxTree, Error> {
+    parse_reader_with_encoding(reader, Encoding::Utf8)
+}
+
+/// Parses a whole program from text in the given encoding, reading it as parsing goes.
+pub fn parse_reader_with_encoding<R: Read>(
+    reader: R,
+    encoding: Encoding,
+) -> Result<SyntaxTree, Error> {
+    let mut reader = BufReader::new(reader);
+    let mut io_error = None;
+    let chars = iter::from_fn(|| {
+        encoding
+            .read_char(&mut reader)
+            .map_err(|e| io_error = Some(e))
+            .ok()
+            .flatten()
+    });
+    let res = parse_chars(chars);
+    match io_error {
+        // The parse error, if any, is only a consequence of the input being cut short.
+        Some(e) => Err(e.into()),
+        None => res,
+    }
+}
+
+/// Parses a term, recording errors instead of returning them.
+fn parse_or_recover<I: Iterator<Item = CharPos>>(
+    iterator: &mut Peekable<I>,
+    errors: &mut Vec<Error>,
+) -> SyntaxTree {
+    // As in `parse`, applications waiting for operands are kept on an explicit stack.
+    let mut pending: Vec<(CharPos, Option<SyntaxTree>)> = Vec::new();
+    loop {
+        consume_whitespace(iterator);
+        let term = match next_token(iterator) {
+            Ok(Token::Combinator(c, span, _)) => SyntaxTree::Combinator(c, span),
+            Ok(Token::Reference(name, span)) => SyntaxTree::Reference(name, span),
+            Ok(Token::Application(token)) => {
+                pending.push((token, None));
+                continue;
+            }
+            Err(e) => recover(e, pending.last().map(|&(token, _)| token), errors),
+        };
+        if let Some(st) = complete(&mut pending, term) {
+            return st;
+        }
+    }
+}
+
+/// Records an error met while parsing a term, returning the error node that takes its place.
+/// `waiting` is the backquote of the innermost application the term belongs to.
+fn recover(e: Error, waiting: Option<CharPos>, errors: &mut Vec<Error>) -> SyntaxTree {
+    let e = match (e, waiting) {
+        (Error::UnexpectedEof { after: None, .. }, Some(token)) => eof_after(token),
+        (e, _) => e,
+    };
+    let span = match e {
+        Error::UnexpectedToken { position, .. }
+        | Error::UnexpectedEof {
+            position: Some(position),
+            ..
+        } => Span::at(position),
+        _ => Span::at((0, 0)),
+    };
+    // Once the input has ended, every enclosing application is missing an operand too; only the
+    // innermost one is worth reporting.
+    let eof = |e: &Error| matches!(e, Error::UnexpectedEof { .. });
+    if !(eof(&e) && errors.last().is_some_and(eof)) {
+        errors.push(e);
+    }
+    SyntaxTree::Error(span)
+}
+
+/// Parses a whole program like `parse_toplevel`, but carries on after errors instead of stopping
+/// at the first one. Each term that cannot be parsed is recorded and replaced with a
+/// `SyntaxTree::Error`, so the tree is complete if and only if no errors are returned.
+pub fn parse_recovering<I: Iterator<Item = CharPos>>(
+    iterator: &mut Peekable<I>,
+) -> (SyntaxTree, Vec<Error>) {
+    let mut errors = Vec::new();
+    let st = parse_or_recover(iterator, &mut errors);
+    consume_whitespace(iterator);
+    if let Some(cp) = iterator.next() {
+        errors.push(Error::TrailingInput {
+            ch: cp.item,
+            position: cp.position,
+        });
+    }
+    (st, errors)
+}
+
+/// Whitespace or a comment, as kept by the lossless parser.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Trivia {
+    Whitespace(String),
+    /// A comment, from its `#` up to but not including the newline that ends it.
+    Comment(String),
+}
+
+/// A token as written, along with the trivia around it. Whitespace on the same line after a token,
+/// up to and including an end-of-line comment, is its trailing trivia; everything else between
+/// two tokens is leading trivia of the second one.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CstToken {
+    pub leading: Vec<Trivia>,
+    /// The token's text, e.g. `K` or `r` rather than `k` or `.` followed by a newline.
+    pub text: String,
+    pub trailing: Vec<Trivia>,
+}
+
+/// A term in a concrete syntax tree, which keeps all of the source text it was parsed from.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CstNode {
+    pub kind: CstKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CstKind {
+    Combinator(Combinator, CstToken),
+    /// A `$name` reference, along with the name without the `$`.
+    Reference(String, CstToken),
+    /// An application, along with the backquote or `[` that introduced it.
+    Application {
+        backquote: CstToken,
+        func: Box<CstNode>,
+        arg: Box<CstNode>,
+    },
+}
+
+/// A lossless parse of a whole program: displaying it gives back the exact source text.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Cst {
+    pub root: CstNode,
+    /// The whitespace and comments after the last token's trailing trivia.
+    pub trailing: Vec<Trivia>,
+}
+
+impl Cst {
+    /// Drops the trivia, giving the tree `parse_toplevel` would have produced.
+    pub fn to_syntax_tree(&self) -> SyntaxTree {
+        self.root.to_syntax_tree()
+    }
+}
+
+impl CstNode {
+    pub fn to_syntax_tree(&self) -> SyntaxTree {
+        // Nodes still to convert, and applications whose operands have been converted, in the
+        // order their trees are needed.
+        enum Step<'a> {
+            Node(&'a CstNode),
+            Apply(Span),
+        }
+        let mut steps = vec![Step::Node(self)];
+        let mut trees = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Node(node) => match &node.kind {
+                    CstKind::Combinator(c, _) => trees.push(SyntaxTree::Combinator(*c, node.span)),
+                    CstKind::Reference(name, _) => {
+                        trees.push(SyntaxTree::Reference(name.clone(), node.span))
+                    }
+                    CstKind::Application { func, arg, .. } => {
+                        steps.push(Step::Apply(node.span));
+                        steps.push(Step::Node(arg));
+                        steps.push(Step::Node(func));
+                    }
+                },
+                Step::Apply(span) => {
+                    let arg = trees.pop().unwrap();
+                    let func = trees.pop().unwrap();
+                    trees.push(SyntaxTree::Application(Application {
+                        func: Box::new(func),
+                        arg: Box::new(arg),
+                        span,
+                    }));
+                }
+            }
+        }
+        trees.pop().unwrap()
+    }
+}
+
+impl Drop for CstNode {
+    // Like `Application`, nested applications are dropped one at a time rather than recursively.
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        take_cst_applications(self, &mut pending);
+        while let Some(mut node) = pending.pop() {
+            take_cst_applications(&mut node, &mut pending);
+        }
+    }
+}
+
+/// Moves the operands of `node`, if it is an application, that are themselves applications to
+/// `into`, leaving empty references in their place.
+fn take_cst_applications(node: &mut CstNode, into: &mut Vec<CstNode>) {
+    let span = node.span;
+    if let CstKind::Application { func, arg, .. } = &mut node.kind {
+        for operand in [func, arg] {
+            if let CstKind::Application { .. } = operand.kind {
+                let empty = CstToken {
+                    leading: Vec::new(),
+                    text: String::new(),
+                    trailing: Vec::new(),
+                };
+                let placeholder = CstNode {
+                    kind: CstKind::Reference(String::new(), empty),
+                    span,
+                };
+                into.push(mem::replace(operand, placeholder));
+            }
+        }
+    }
+}
+
+impl fmt::Display for Trivia {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trivia::Whitespace(text) | Trivia::Comment(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+impl fmt::Display for CstToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for trivia in &self.leading {
+            write!(f, "{}", trivia)?;
+        }
+        write!(f, "{}", self.text)?;
+        for trivia in &self.trailing {
+            write!(f, "{}", trivia)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CstNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut pending = vec![self];
+        while let Some(node) = pending.pop() {
+            match &node.kind {
+                CstKind::Combinator(_, token) | CstKind::Reference(_, token) => {
+                    write!(f, "{}", token)?
+                }
+                CstKind::Application {
+                    backquote,
+                    func,
+                    arg,
+                } => {
+                    write!(f, "{}", backquote)?;
+                    pending.push(arg);
+                    pending.push(func);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Cst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.root)?;
+        for trivia in &self.trailing {
+            write!(f, "{}", trivia)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads whitespace and comments. With `same_line`, stops at the first newline.
+fn read_trivia<I: Iterator<Item = CharPos>>(
+    iterator: &mut Peekable<I>,
+    same_line: bool,
+) -> Vec<Trivia> {
+    let is_space = |ch: char| ch.is_whitespace() && !(same_line && ch == '\n');
+    let mut trivia = Vec::new();
+    while let Some(c) = iterator.peek() {
+        let comment = c.item == '#';
+        if !comment && !is_space(c.item) {
+            break;
+        }
+        let belongs = |ch: char| if comment { ch != '\n' } else { is_space(ch) };
+        let mut text = String::new();
+        while let Some(c) = iterator.peek().filter(|c| belongs(c.item)) {
+            text.push(c.item);
+            iterator.next();
+        }
+        trivia.push(if comment {
+            Trivia::Comment(text)
+        } else {
+            Trivia::Whitespace(text)
+        });
+    }
+    trivia
+}
+
+fn parse_cst_node<I: Iterator<Item = CharPos>>(
+    iterator: &mut Peekable<I>,
+) -> Result<CstNode, Error> {
+    // Applications waiting for operands, as in `parse`: their backquote as read and as a token,
+    // and their function once it has been parsed.
+    let mut pending: Vec<(CharPos, CstToken, Option<CstNode>)> = Vec::new();
+    loop {
+        let leading = read_trivia(iterator, false);
+        let next = next_token(iterator).map_err(|e| match pending.last() {
+            Some(&(first, _, _)) => blame_application(first)(e),
+            None => e,
+        })?;
+        let token = |iterator: &mut Peekable<I>, text: String| CstToken {
+            leading,
+            text,
+            trailing: read_trivia(iterator, true),
+        };
+        let mut node = match next {
+            Token::Combinator(c, span, first) => {
+                let mut text = first.to_string();
+                if let (Combinator::Compare(ch), '?') | (Combinator::Dot(ch), '.') = (c, first) {
+                    text.push(ch);
+                }
+                CstNode {
+                    kind: CstKind::Combinator(c, token(iterator, text)),
+                    span,
+                }
+            }
+            Token::Reference(name, span) => {
+                let text = format!("${}", name);
+                CstNode {
+                    kind: CstKind::Reference(name, token(iterator, text)),
+                    span,
+                }
+            }
+            Token::Application(first) => {
+                let backquote = token(iterator, first.item.to_string());
+                pending.push((first, backquote, None));
+                continue;
+            }
+        };
+        loop {
+            match pending.last_mut() {
+                None => return Ok(node),
+                Some((_, _, func @ None)) => {
+                    *func = Some(node);
+                    break;
+                }
+                Some((_, _, Some(_))) => {
+                    let (first, backquote, func) = pending.pop().unwrap();
+                    node = CstNode {
+                        span: Span {
+                            start: first.position,
+                            end: node.span.end,
+                        },
+                        kind: CstKind::Application {
+                            backquote,
+                            func: Box::new(func.unwrap()),
+                            arg: Box::new(node),
+                        },
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Parses a whole program like `parse_toplevel`, but keeps comments and whitespace.
+pub fn parse_cst<I: Iterator<Item = CharPos>>(iterator: &mut Peekable<I>) -> Result<Cst, Error> {
+    let root = parse_cst_node(iterator)?;
+    let trailing = read_trivia(iterator, false);
+    if let Some(cp) = iterator.next() {
+        Err(Error::TrailingInput {
+            ch: cp.item,
+            position: cp.position,
+        })
+    } else {
+        Ok(Cst { root, trailing })
+    }
+}
+
 pub struct CharPosIterator<I: Iterator<Item = char>> {
     chars: I,
     col: usize,
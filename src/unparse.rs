@@ -0,0 +1,264 @@
+// Copyright 2019 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! unparse.rs - Turning syntax trees back into source text
+//! Comments are not part of the syntax tree, so `unparse` loses them; `unparse_cst` works from a
+//! concrete syntax tree instead and keeps them.
+
+use crate::parse::{
+    is_name_char, Application, Combinator, Cst, CstKind, CstNode, CstToken, SyntaxTree, Trivia,
+};
+
+/// How `unparse` lays out the program.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Layout {
+    /// The shortest form: lowercase, no whitespace, and `r` for `.` followed by a newline.
+    Canonical,
+    /// One operand per line, indented two spaces under the backquote that applies it. Applications
+    /// of a combinator to a combinator are kept on a single line.
+    Indented,
+}
+
+const INDENT: &str = "  ";
+
+/// Writes a syntax tree back out as source text. Error nodes, which have no source form, are
+/// written as `<error>`.
+pub fn unparse(st: &SyntaxTree, layout: Layout) -> String {
+    let mut out = Output::default();
+    match layout {
+        Layout::Canonical => unparse_canonical(st, &mut out),
+        Layout::Indented => unparse_indented(st, 0, &mut out),
+    }
+    out.text
+}
+
+/// Like `unparse`, but keeps the comments of a concrete syntax tree. A comment that ended a line
+/// still ends the line of the token it followed; other comments go on lines of their own, just
+/// before the token that followed them. Whitespace is laid out afresh.
+pub fn unparse_cst(cst: &Cst, layout: Layout) -> String {
+    let mut printer = Printer {
+        out: Output::default(),
+        layout,
+        in_comment: false,
+    };
+    printer.node(&cst.root, 0);
+    for comment in comments(&cst.trailing) {
+        printer.comment_line(comment, 0);
+    }
+    printer.out.text
+}
+
+pub fn combinator_to_string(c: Combinator) -> String {
+    match c {
+        Combinator::I => "i".to_string(),
+        Combinator::K => "k".to_string(),
+        Combinator::S => "s".to_string(),
+        Combinator::V => "v".to_string(),
+        Combinator::D => "d".to_string(),
+        Combinator::C => "c".to_string(),
+        Combinator::E => "e".to_string(),
+        Combinator::Read => "@".to_string(),
+        Combinator::Reprint => "|".to_string(),
+        Combinator::Compare(ch) => format!("?{}", ch),
+        Combinator::Dot('\n') => "r".to_string(),
+        Combinator::Dot(ch) => format!(".{}", ch),
+    }
+}
+
+/// Source text being written out.
+#[derive(Default)]
+struct Output {
+    text: String,
+    /// Where the last reference written ends, if nothing has been written since.
+    reference_end: Option<usize>,
+}
+
+impl Output {
+    /// Writes a token, separating it from a reference it would otherwise extend.
+    fn token(&mut self, token: &str) {
+        if self.reference_end == Some(self.text.len()) && token.starts_with(is_name_char) {
+            self.text.push(' ');
+        }
+        self.text.push_str(token);
+        if token.starts_with('$') {
+            self.reference_end = Some(self.text.len());
+        }
+    }
+
+    fn push(&mut self, ch: char) {
+        self.text.push(ch);
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.text.push_str(s);
+    }
+}
+
+fn unparse_canonical(st: &SyntaxTree, out: &mut Output) {
+    // Trees are walked with an explicit stack, so that nesting can go arbitrarily deep.
+    let mut pending = vec![st];
+    while let Some(st) = pending.pop() {
+        match st {
+            SyntaxTree::Combinator(c, _) => out.token(&combinator_to_string(*c)),
+            SyntaxTree::Application(Application { func, arg, .. }) => {
+                out.push('`');
+                pending.push(arg);
+                pending.push(func);
+            }
+            SyntaxTree::Error(_) => out.push_str("<error>"),
+            SyntaxTree::Reference(name, _) => out.token(&format!("${}", name)),
+        }
+    }
+}
+
+/// What is left to write of an indented tree.
+enum Step<'a, T> {
+    /// A tree or node, along with its depth.
+    Tree(&'a T, usize),
+    /// A line break, followed by indentation for the given depth.
+    NewLine(usize),
+}
+
+fn unparse_indented(st: &SyntaxTree, depth: usize, out: &mut Output) {
+    let mut steps = vec![Step::Tree(st, depth)];
+    while let Some(step) = steps.pop() {
+        match step {
+            Step::Tree(SyntaxTree::Application(Application { func, arg, .. }), depth)
+                if !(is_combinator(func) && is_combinator(arg)) =>
+            {
+                out.push('`');
+                steps.push(Step::Tree(arg, depth + 1));
+                steps.push(Step::NewLine(depth + 1));
+                steps.push(Step::Tree(func, depth + 1));
+                steps.push(Step::NewLine(depth + 1));
+            }
+            Step::Tree(st, _) => unparse_canonical(st, out),
+            Step::NewLine(depth) => {
+                out.push('\n');
+                for _ in 0..depth {
+                    out.push_str(INDENT);
+                }
+            }
+        }
+    }
+}
+
+fn is_combinator(st: &SyntaxTree) -> bool {
+    match st {
+        SyntaxTree::Combinator(_, _) | SyntaxTree::Error(_) | SyntaxTree::Reference(_, _) => true,
+        SyntaxTree::Application(_) => false,
+    }
+}
+
+struct Printer {
+    out: Output,
+    layout: Layout,
+    /// Whether the current line ends in a comment, so that nothing else can go on it.
+    in_comment: bool,
+}
+
+impl Printer {
+    fn node(&mut self, node: &CstNode, depth: usize) {
+        let mut steps = vec![Step::Tree(node, depth)];
+        while let Some(step) = steps.pop() {
+            let (node, depth) = match step {
+                Step::Tree(node, depth) => (node, depth),
+                Step::NewLine(depth) => {
+                    self.new_line(depth);
+                    continue;
+                }
+            };
+            match &node.kind {
+                CstKind::Combinator(c, token) => {
+                    self.token(token, &combinator_to_string(*c), depth)
+                }
+                CstKind::Reference(_, token) => self.token(token, &token.text, depth),
+                CstKind::Application {
+                    backquote,
+                    func,
+                    arg,
+                } => {
+                    self.token(backquote, "`", depth);
+                    let inline = self.layout == Layout::Canonical
+                        || (!has_comments(backquote)
+                            && is_bare_combinator(func)
+                            && is_bare_combinator(arg));
+                    if inline {
+                        steps.push(Step::Tree(arg, depth));
+                        steps.push(Step::Tree(func, depth));
+                    } else {
+                        steps.push(Step::Tree(arg, depth + 1));
+                        steps.push(Step::NewLine(depth + 1));
+                        steps.push(Step::Tree(func, depth + 1));
+                        steps.push(Step::NewLine(depth + 1));
+                    }
+                }
+            }
+        }
+    }
+
+    fn token(&mut self, token: &CstToken, text: &str, depth: usize) {
+        for comment in comments(&token.leading) {
+            self.comment_line(comment, depth);
+            self.new_line(depth);
+        }
+        if self.in_comment {
+            self.new_line(depth);
+        }
+        self.out.token(text);
+        for comment in comments(&token.trailing) {
+            self.out.push(' ');
+            self.out.push_str(comment);
+            self.in_comment = true;
+        }
+    }
+
+    /// Writes a comment at the start of a line.
+    fn comment_line(&mut self, comment: &str, depth: usize) {
+        if !self.out.text.rsplit('\n').next().unwrap().trim().is_empty() {
+            self.new_line(depth);
+        }
+        self.out.push_str(comment);
+        self.in_comment = true;
+    }
+
+    fn new_line(&mut self, depth: usize) {
+        self.out.push('\n');
+        for _ in 0..depth {
+            self.out.push_str(INDENT);
+        }
+        self.in_comment = false;
+    }
+}
+
+fn comments(trivia: &[Trivia]) -> impl Iterator<Item = &str> {
+    trivia.iter().filter_map(|t| match t {
+        Trivia::Comment(text) => Some(text.as_str()),
+        Trivia::Whitespace(_) => None,
+    })
+}
+
+fn has_comments(token: &CstToken) -> bool {
+    comments(&token.leading)
+        .chain(comments(&token.trailing))
+        .next()
+        .is_some()
+}
+
+fn is_bare_combinator(node: &CstNode) -> bool {
+    match &node.kind {
+        CstKind::Combinator(_, token) | CstKind::Reference(_, token) => !has_comments(token),
+        CstKind::Application { .. } => false,
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2019 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{self, Command, Output, Stdio};
+
+fn relambda(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_main"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Runs `relambda` with the given stdin, returning what it printed to stdout and stderr.
+fn relambda_with_input(args: &[&str], input: &[u8]) -> (String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_main"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+/// Runs a silent REPL on the given input, returning what it printed to stdout and stderr.
+fn repl(input: &str) -> (String, String) {
+    relambda_with_input(&["-s"], input.as_bytes())
+}
+
+fn exit_status(args: &[&str]) -> i32 {
+    relambda(args).status.code().unwrap()
+}
+
+#[test]
+fn test_exit_status() {
+    assert_eq!(exit_status(&["-e", "`.ai"]), 0);
+    assert_eq!(exit_status(&["-e", "``.a`eki"]), 3);
+    assert_eq!(exit_status(&["-e", "``"]), 1);
+    assert_eq!(exit_status(&["does-not-exist.unl"]), 1);
+}
+
+#[test]
+fn test_exit_code_church() {
+    let church = |program| exit_status(&["-e", program, "--exit-code", "church"]);
+    assert_eq!(church("`ki"), 0);
+    assert_eq!(church("i"), 1);
+    assert_eq!(church("``s``s`ksk``s``s`kski"), 3);
+    assert_eq!(church("`e``s``s`ksk`ki"), 1);
+    // Values that are not numerals, even though applying them terminates.
+    assert_eq!(church("k"), 6);
+    assert_eq!(church("v"), 6);
+    assert_eq!(church("`kk"), 6);
+    // Decoding never terminates.
+    assert_eq!(church("`d```sii``sii"), 6);
+}
+
+#[test]
+fn test_exit_code_bool() {
+    let bool = |program| exit_status(&["-e", program, "--exit-code", "bool"]);
+    assert_eq!(bool("i"), 0);
+    assert_eq!(bool("``kvi"), 1);
+    assert_eq!(bool("v"), 1);
+    assert_eq!(bool("k"), 6);
+}
+
+#[test]
+fn test_repl_definitions() {
+    let file = env::temp_dir().join(format!("relambda-defs-{}.unl", process::id()));
+    let file = file.to_str().unwrap();
+    let (_, errors) = repl(&format!(
+        "`ki\n:def x = $_\n:def y = `k$1\n:def two = ``s``s`kski\n:save {}\n",
+        file
+    ));
+    assert_eq!(
+        errors,
+        "error: definitions cannot refer to results such as `$_`, only to other definitions\n\
+         error: definitions cannot refer to results such as `$1`, only to other definitions\n"
+    );
+    let (output, errors) = repl(&format!(":load {}\n:defs\n``$two .* i\n", file));
+    fs::remove_file(file).unwrap();
+    assert_eq!(output, "two = ``s``s`kski\n**");
+    assert_eq!(errors, "");
+}
+
+#[test]
+fn test_repl_errors() {
+    let (_, errors) = repl(":def id = i\n``k$id\n  $nope\n:frobnicate\n");
+    assert_eq!(
+        errors,
+        "\
+error: unbound reference `$nope`
+ --> <repl>:2:3
+  |
+2 |   $nope
+  |   ^^^^^ nothing is defined with this name
+error: unknown command `:frobnicate`, try :help
+"
+    );
+}
+
+#[test]
+fn test_unreadable_files() {
+    let stderr = |args| String::from_utf8(relambda(args).stderr).unwrap();
+    assert!(stderr(&["does-not-exist.unl"]).starts_with("error: cannot read does-not-exist.unl: "));
+    assert!(stderr(&["--input", "does-not-exist", "-e", "`@i"])
+        .starts_with("error: cannot read does-not-exist: "));
+    assert!(stderr(&["check", "does-not-exist.unl"])
+        .starts_with("error: cannot read does-not-exist.unl: "));
+}
+
+#[test]
+fn test_repl_errors_in_definitions() {
+    // The definition's own text is not shown, so errors in it are blamed on the reference.
+    let input = ":def x =           `.\u{101}\ni\n`$x\ni\n";
+    let (_, errors) = relambda_with_input(&["-s", "--encoding", "latin-1"], input.as_bytes());
+    assert_eq!(
+        errors,
+        "\
+error: I/O error: `\u{101}` cannot be written as Latin-1
+ --> <repl>:1:2
+  |
+1 | `$x
+  |  ^^ while evaluating this
+"
+    );
+}
+
+#[test]
+fn test_debugger_errors() {
+    let file = env::temp_dir().join(format!("relambda-debug-{}.unl", process::id()));
+    fs::write(&file, "`k\n  ``.a@i").unwrap();
+    let file = file.to_str().unwrap();
+    // `@` reads from stdin after the debugger's own commands, and fails on invalid UTF-8.
+    let (_, errors) = relambda_with_input(&["debug", file], b"continue\n\xff\n");
+    fs::remove_file(file).unwrap();
+    assert_eq!(
+        errors,
+        format!(
+            "\
+error: I/O error: Bad UTF-8: [255, 10]
+ --> {}:2:3
+  |
+2 |   ``.a@i
+  |   ^^^^^^ while evaluating this
+",
+            file
+        )
+    );
+}
@@ -12,12 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::io::{self, Read, Write};
 use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use lazy_static::{initialize, lazy_static};
 use log::Level;
 
-use relambda::{parse_compile_run, Expression, Function};
+use relambda::parse::{
+    parse_chars, parse_cst, parse_reader, parse_reader_with_encoding, parse_recovering,
+    parse_toplevel, CharPosIterator, Cst, CstKind, Span, SyntaxTree, Trivia,
+};
+use relambda::unparse::{unparse, unparse_cst, Layout};
+use relambda::{
+    parse_compile_run, parse_compile_run_with_io, parse_compile_run_with_limits, run_capture,
+    run_capture_with_limits, run_to_termination, Encoding, Error, Expression, Function,
+    InterruptHandle, Limit, OpCode, ReadWriteIo, RunLimits, Status, StringIo, Termination, Vm,
+};
 
 lazy_static! {
     static ref LOGGER: () = {
@@ -35,15 +47,15 @@ fn setup_logging() {
 #[test]
 fn test_iks_basic() {
     setup_logging();
-    assert_eq!(parse_compile_run(&"```skss").unwrap(), Function::S);
-    assert_eq!(parse_compile_run(&"`ii").unwrap(), Function::I);
-    assert_eq!(parse_compile_run(&"``ksi").unwrap(), Function::S)
+    assert_eq!(parse_compile_run("```skss").unwrap(), Function::S);
+    assert_eq!(parse_compile_run("`ii").unwrap(), Function::I);
+    assert_eq!(parse_compile_run("``ksi").unwrap(), Function::S)
 }
 
 #[test]
 fn test_d_promise() {
     setup_logging();
-    let suspended = parse_compile_run(&"`d`ir").unwrap();
+    let suspended = parse_compile_run("`d`ir").unwrap();
     match suspended {
         Function::D1(_) => (),
         _ => panic!("expected promise"),
@@ -53,9 +65,9 @@ fn test_d_promise() {
 #[test]
 fn test_force_promise() {
     setup_logging();
-    assert_eq!(parse_compile_run(&"``d`iri").unwrap(), Function::I);
+    assert_eq!(parse_compile_run("``d`iri").unwrap(), Function::I);
     assert_eq!(
-        parse_compile_run(&"``d```skssi").unwrap(),
+        parse_compile_run("``d```skssi").unwrap(),
         Function::S1(Rc::new(Function::I))
     );
 }
@@ -63,20 +75,20 @@ fn test_force_promise() {
 #[test]
 fn test_call_cc() {
     setup_logging();
-    assert_eq!(parse_compile_run(&"``cii").unwrap(), Function::I);
-    assert_eq!(parse_compile_run(&"``cir").unwrap(), Function::Dot('\n'));
-    assert_eq!(parse_compile_run(&"`c``s`kr``si`ki").unwrap(), Function::I);
+    assert_eq!(parse_compile_run("``cii").unwrap(), Function::I);
+    assert_eq!(parse_compile_run("``cir").unwrap(), Function::Dot('\n'));
+    assert_eq!(parse_compile_run("`c``s`kr``si`ki").unwrap(), Function::I);
 }
 
 #[test]
 fn test_iv_boolean() {
     setup_logging();
     assert_eq!(
-        parse_compile_run(&"`````s`kc``s`k`s`k`k`ki``ss`k`kkiks").unwrap(),
+        parse_compile_run("`````s`kc``s`k`s`k`k`ki``ss`k`kkiks").unwrap(),
         Function::K
     );
     assert_eq!(
-        parse_compile_run(&"`````s`kc``s`k`s`k`k`ki``ss`k`kkvks").unwrap(),
+        parse_compile_run("`````s`kc``s`k`s`k`k`ki``ss`k`kkvks").unwrap(),
         Function::S
     );
 }
@@ -85,7 +97,7 @@ fn test_iv_boolean() {
 fn test_invoke_d() {
     setup_logging();
     assert_eq!(
-        parse_compile_run(&"```sddk").unwrap(),
+        parse_compile_run("```sddk").unwrap(),
         Function::K1(Rc::new(Function::D1(Expression::Function(Rc::new(
             Function::K
         )))))
@@ -95,7 +107,920 @@ fn test_invoke_d() {
 #[test]
 fn test_s_d() {
     setup_logging();
-    assert_eq!(parse_compile_run(&"````sdi`kii").unwrap(), Function::I);
+    assert_eq!(parse_compile_run("````sdi`kii").unwrap(), Function::I);
+}
+
+#[test]
+fn test_string_io() {
+    setup_logging();
+    let mut io = StringIo::new("");
+    assert_eq!(
+        parse_compile_run_with_io("`r`.!`.i`.Hi", &mut io).unwrap(),
+        Function::I
+    );
+    assert_eq!(io.output(), "Hi!\n");
+
+    let mut io = StringIo::new("xyz");
+    assert_eq!(
+        parse_compile_run_with_io("``@i``|ii", &mut io).unwrap(),
+        Function::I
+    );
+    assert_eq!(io.into_output(), "x");
+}
+
+#[test]
+fn test_read_eof() {
+    setup_logging();
+    let mut io = StringIo::new("");
+    assert_eq!(
+        parse_compile_run_with_io("``@i`|i", &mut io).unwrap(),
+        Function::V
+    );
+    assert_eq!(io.output(), "");
+}
+
+#[test]
+fn test_read_write_io() {
+    setup_logging();
+    let mut io = ReadWriteIo::new("é?".as_bytes(), Vec::new());
+    assert_eq!(
+        parse_compile_run_with_io("``@i``|ii", &mut io).unwrap(),
+        Function::I
+    );
+    assert_eq!(
+        parse_compile_run_with_io("``@i``|ii", &mut io).unwrap(),
+        Function::I
+    );
+    assert_eq!(io.into_inner().1, "é?".as_bytes());
+}
+
+#[test]
+fn test_latin1_io() {
+    setup_logging();
+    let mut io = ReadWriteIo::with_encoding(&b"\xff\xe9"[..], Vec::new(), Encoding::Latin1);
+    // Reads ÿ and prints it back, then reads é and prints `y` if it compares equal.
+    assert!(parse_compile_run_with_io("``@i``|ii", &mut io).is_ok());
+    assert!(parse_compile_run_with_io("``@i``?\u{e9}.yi", &mut io).is_ok());
+    assert_eq!(io.into_inner().1, b"\xffy");
+
+    let mut io = ReadWriteIo::with_encoding(io::empty(), Vec::new(), Encoding::Latin1);
+    match parse_compile_run_with_io("`.\u{20ac}i", &mut io) {
+        Err(Error::Io { error, .. }) => assert_eq!(error.kind(), io::ErrorKind::InvalidData),
+        r => panic!("expected an I/O error, got {:?}", r),
+    }
+
+    assert_eq!(
+        Encoding::Latin1.decode(b"`.\xe9i".to_vec()).unwrap(),
+        "`.éi"
+    );
+    assert_eq!(Encoding::Latin1.encode("`.éi").unwrap(), b"`.\xe9i");
+    assert!(Encoding::Utf8.decode(b"`.\xe9i".to_vec()).is_err());
+    assert_eq!(
+        parse_reader_with_encoding(&b"`.\xe9i"[..], Encoding::Latin1).unwrap(),
+        parse_str("`.éi")
+    );
+}
+
+#[test]
+fn test_run_capture() {
+    setup_logging();
+    let outcome = run_capture("``@i``|ii", "xyz").unwrap();
+    assert_eq!(outcome.value, Function::I);
+    assert_eq!(outcome.stdout, "x");
+    assert!(!outcome.exited_via_e);
+    assert!(outcome.steps > 0);
+
+    let outcome = run_capture("``.a`ei.b", "").unwrap();
+    assert_eq!(outcome.value, Function::I);
+    assert_eq!(outcome.stdout, "");
+    assert!(outcome.exited_via_e);
+
+    let outcome = run_capture("`r`.a`ek", "").unwrap();
+    assert_eq!(outcome.value, Function::K);
+    assert!(outcome.exited_via_e);
+
+    let limits = RunLimits {
+        max_steps: Some(100),
+        ..Default::default()
+    };
+    assert!(matches!(
+        run_capture_with_limits("```sii``sii", "", &limits),
+        Err(Error::LimitExceeded {
+            limit: Limit::Steps,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_termination() {
+    setup_logging();
+    let run = |code: &str, limits: &RunLimits| {
+        run_to_termination(code, &mut StringIo::new(""), limits).unwrap()
+    };
+    let unlimited = RunLimits::default();
+    assert!(
+        matches!(run("`ki", &unlimited), Termination::Finished(v) if *v == Function::K1(Rc::new(Function::I)))
+    );
+    assert!(matches!(run("``.a`eki", &unlimited), Termination::Exited(v) if *v == Function::K));
+    let limits = RunLimits {
+        max_steps: Some(100),
+        ..Default::default()
+    };
+    assert!(matches!(
+        run("```sii``sii", &limits),
+        Termination::LimitExceeded {
+            limit: Limit::Steps,
+            steps: 100,
+            ..
+        }
+    ));
+    let termination = run_to_termination(
+        "`.ai",
+        &mut ReadWriteIo::new(io::empty(), FailingWriter),
+        &unlimited,
+    )
+    .unwrap();
+    assert!(matches!(termination, Termination::Io { .. }));
+    assert!(matches!(termination.into_result(), Err(Error::Io { .. })));
+    assert!(matches!(
+        run_to_termination("`k", &mut StringIo::new(""), &unlimited),
+        Err(Error::UnexpectedEof { .. })
+    ));
+
+    let mut vm = Vm::new("`@i").unwrap();
+    assert!(matches!(
+        vm.run_to_termination(&mut StringIo::interactive()),
+        Termination::Io { error, .. } if error.kind() == io::ErrorKind::WouldBlock
+    ));
+}
+
+#[test]
+fn test_vm_apply() {
+    setup_logging();
+    // Church numeral 2, applied to `.*` then `i`, prints two stars.
+    let mut vm = Vm::new("``s``s`kski").unwrap();
+    let mut io = StringIo::new("");
+    let two = match vm.run(&mut io).unwrap() {
+        Status::Finished(v) => v,
+        s => panic!("expected a value, got {:?}", s),
+    };
+    vm.apply(two, Rc::new(Function::Dot('*')));
+    let twice = match vm.run(&mut io).unwrap() {
+        Status::Finished(v) => v,
+        s => panic!("expected a value, got {:?}", s),
+    };
+    vm.apply(twice, Rc::new(Function::I));
+    assert!(matches!(vm.run(&mut io).unwrap(), Status::Finished(v) if *v == Function::I));
+    assert_eq!(io.output(), "**");
+
+    // Promises keep pointing into the original code.
+    let mut vm = Vm::new("`d`.xi").unwrap();
+    let promise = match vm.run(&mut io).unwrap() {
+        Status::Finished(v) => v,
+        s => panic!("expected a value, got {:?}", s),
+    };
+    vm.apply(promise, Rc::new(Function::K));
+    assert!(matches!(vm.run(&mut io).unwrap(), Status::Finished(v) if *v == Function::K));
+    assert_eq!(io.output(), "**x");
+
+    // The VM can be reused after `e`.
+    let mut vm = Vm::new("`ek").unwrap();
+    assert!(matches!(vm.run(&mut io).unwrap(), Status::Exited(_)));
+    vm.apply(Rc::new(Function::E), Rc::new(Function::S));
+    assert!(matches!(vm.run(&mut io).unwrap(), Status::Exited(v) if *v == Function::S));
+}
+
+#[test]
+fn test_vm_bind_load() {
+    setup_logging();
+    let mut io = StringIo::new("");
+    let mut vm = Vm::new("`d`.xi").unwrap();
+    let promise = match vm.run(&mut io).unwrap() {
+        Status::Finished(v) => v,
+        s => panic!("expected a value, got {:?}", s),
+    };
+    vm.bind("p", promise.clone());
+    assert!(matches!(
+        vm.load(&parse_str("`$q i")),
+        Err(Error::UnboundReference { ref name, .. }) if name == "q"
+    ));
+
+    // Bound values are rendered in full by the VM, and as `<value>` without it.
+    vm.load(&parse_str("`d`$p k")).unwrap();
+    let delayed = match vm.run(&mut io).unwrap() {
+        Status::Finished(v) => v,
+        s => panic!("expected a value, got {:?}", s),
+    };
+    assert_eq!(vm.to_unlambda(&delayed), "`d``d`.xik");
+    assert_eq!(delayed.to_unlambda(vm.code()), "`d`<value>k");
+
+    // Rebinding a name does not change programs loaded before.
+    vm.bind("p", Rc::new(Function::I));
+    vm.load(&parse_str("``$p $d i")).unwrap_err();
+    vm.bind("d", delayed);
+    vm.load(&parse_str("``$p $d i")).unwrap();
+    assert!(matches!(
+        vm.run(&mut io).unwrap(),
+        Status::Finished(v) if *v == Function::K1(Rc::new(Function::I))
+    ));
+    assert_eq!(io.output(), "x");
+
+    // Continuations from earlier programs return to where they were captured.
+    let mut vm = Vm::new("`.a`ci").unwrap();
+    let k = match vm.run(&mut io).unwrap() {
+        Status::Finished(v) => v,
+        s => panic!("expected a value, got {:?}", s),
+    };
+    vm.bind("k", k);
+    vm.load(&parse_str("`.b`$k .c")).unwrap();
+    assert!(matches!(vm.run(&mut io).unwrap(), Status::Finished(v) if *v == Function::Dot('c')));
+    assert_eq!(io.output(), "xaa");
+}
+
+#[test]
+fn test_parse_errors() {
+    setup_logging();
+    match parse_compile_run("``ki\n  `k") {
+        Err(Error::UnexpectedEof {
+            after: Some('`'),
+            position: Some((1, 2)),
+        }) => (),
+        r => panic!("expected EOF error, got {:?}", r),
+    }
+    match parse_compile_run("`k?") {
+        Err(Error::UnexpectedEof {
+            after: Some('?'),
+            position: Some((0, 2)),
+        }) => (),
+        r => panic!("expected EOF error, got {:?}", r),
+    }
+    match parse_compile_run("# nothing here") {
+        Err(Error::UnexpectedEof { after: None, .. }) => (),
+        r => panic!("expected EOF error, got {:?}", r),
+    }
+    match parse_compile_run("`k\n x") {
+        Err(Error::UnexpectedToken {
+            ch: 'x',
+            position: (1, 1),
+        }) => (),
+        r => panic!("expected unexpected token, got {:?}", r),
+    }
+    match parse_compile_run("`kii") {
+        Err(Error::TrailingInput {
+            ch: 'i',
+            position: (0, 3),
+        }) => (),
+        r => panic!("expected trailing input, got {:?}", r),
+    }
+    assert_eq!(
+        parse_compile_run("`k\n x").unwrap_err().to_string(),
+        "unexpected token `x` at (1, 1)"
+    );
+}
+
+fn limit_hit(code: &str, limits: RunLimits) -> (Limit, u64) {
+    let limits = RunLimits {
+        max_steps: limits.max_steps.or(Some(1_000_000)),
+        ..limits
+    };
+    match parse_compile_run_with_limits(code, &mut StringIo::new(""), &limits) {
+        Err(Error::LimitExceeded { limit, steps, .. }) => (limit, steps),
+        r => panic!("expected limit to be exceeded, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_limits() {
+    setup_logging();
+    let omega = "```sii``sii";
+    let growing = "```s`ki``sii``s`ki``sii";
+    assert_eq!(
+        limit_hit(
+            omega,
+            RunLimits {
+                max_steps: Some(1000),
+                ..Default::default()
+            }
+        ),
+        (Limit::Steps, 1000)
+    );
+    assert_eq!(
+        limit_hit(
+            growing,
+            RunLimits {
+                max_stack_depth: Some(100),
+                ..Default::default()
+            }
+        )
+        .0,
+        Limit::StackDepth
+    );
+    assert_eq!(
+        limit_hit(
+            growing,
+            RunLimits {
+                max_return_depth: Some(100),
+                ..Default::default()
+            }
+        )
+        .0,
+        Limit::ReturnDepth
+    );
+    assert_eq!(
+        limit_hit(
+            "``ci`ci",
+            RunLimits {
+                max_allocations: Some(100),
+                ..Default::default()
+            }
+        )
+        .0,
+        Limit::Allocations
+    );
+    assert_eq!(
+        limit_hit(
+            omega,
+            RunLimits {
+                deadline: Some(Instant::now()),
+                ..Default::default()
+            }
+        ),
+        (Limit::Deadline, 0)
+    );
+    assert_eq!(
+        parse_compile_run_with_limits(
+            "``ksi",
+            &mut StringIo::new(""),
+            &RunLimits {
+                max_steps: Some(100),
+                max_stack_depth: Some(3),
+                ..Default::default()
+            }
+        )
+        .unwrap(),
+        Function::S
+    );
+}
+
+#[test]
+fn test_vm_stepping() {
+    setup_logging();
+    let mut io = StringIo::new("");
+    let mut vm = Vm::new("``.a.bi").unwrap();
+    assert_eq!(vm.step(&mut io).unwrap(), Status::Running);
+    assert_eq!(vm.steps(), 1);
+    let mut status = Status::Running;
+    while status == Status::Running {
+        status = vm.step(&mut io).unwrap();
+    }
+    assert_eq!(status, Status::Finished(Rc::new(Function::I)));
+    assert_eq!(io.output(), "ab");
+    let steps = vm.steps();
+    assert_eq!(vm.run(&mut io).unwrap(), status);
+    assert_eq!(vm.steps(), steps);
+
+    let mut vm = Vm::new("```sii``sii").unwrap();
+    assert_eq!(vm.run_for(10_000, &mut io).unwrap(), Status::Running);
+    assert_eq!(vm.run_for(10_000, &mut io).unwrap(), Status::Running);
+    assert_eq!(vm.steps(), 20_000);
+}
+
+#[test]
+fn test_vm_exit() {
+    setup_logging();
+    let mut io = StringIo::new("");
+    let mut vm = Vm::new("``ek`.xi").unwrap();
+    assert_eq!(
+        vm.run(&mut io).unwrap(),
+        Status::Exited(Rc::new(Function::K))
+    );
+    assert_eq!(io.output(), "");
+}
+
+#[test]
+fn test_vm_resume_after_limit() {
+    setup_logging();
+    let mut io = StringIo::new("");
+    let mut vm = Vm::new("`r`.!`.i`.Hi").unwrap();
+    vm.set_limits(RunLimits {
+        max_steps: Some(10),
+        ..Default::default()
+    });
+    match vm.run(&mut io) {
+        Err(Error::LimitExceeded {
+            limit: Limit::Steps,
+            steps: 10,
+            ..
+        }) => (),
+        r => panic!("expected step limit, got {:?}", r),
+    }
+    vm.set_limits(RunLimits::default());
+    assert_eq!(
+        vm.run(&mut io).unwrap(),
+        Status::Finished(Rc::new(Function::I))
+    );
+    assert_eq!(io.output(), "Hi!\n");
+}
+
+#[test]
+fn test_interrupt_handle() {
+    setup_logging();
+    log::set_max_level(log::LevelFilter::Info);
+    let mut io = StringIo::new("");
+
+    // A pending interrupt stops the VM before its next step, and is then cleared.
+    let mut vm = Vm::new("`.ai").unwrap();
+    let handle = vm.interrupt_handle();
+    handle.interrupt();
+    assert!(handle.is_interrupted());
+    assert!(matches!(
+        vm.run(&mut io),
+        Err(Error::Interrupted { steps: 0, .. })
+    ));
+    assert!(!handle.is_interrupted());
+    assert!(matches!(vm.run(&mut io).unwrap(), Status::Finished(_)));
+    assert_eq!(io.output(), "a");
+
+    // Withdrawn interrupts have no effect.
+    let mut vm = Vm::new("`.bi").unwrap();
+    vm.interrupt_handle().interrupt();
+    vm.interrupt_handle().reset();
+    assert!(matches!(vm.run(&mut io).unwrap(), Status::Finished(_)));
+
+    // A program that never terminates, stopped from another thread.
+    let mut vm = Vm::new("```sii``sii").unwrap();
+    let handle = InterruptHandle::new();
+    vm.set_interrupt_handle(handle.clone());
+    let interrupter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        handle.interrupt();
+    });
+    match vm.run_to_termination(&mut io) {
+        Termination::Interrupted { steps, .. } => {
+            assert!(steps > 0);
+            assert_eq!(steps, vm.steps());
+        }
+        t => panic!("expected an interrupt, got {:?}", t),
+    }
+    interrupter.join().unwrap();
+}
+
+#[test]
+fn test_vm_needs_input() {
+    setup_logging();
+    let mut io = StringIo::interactive();
+    let mut vm = Vm::new("```@i``|ii``@i``|ii").unwrap();
+    assert_eq!(vm.run(&mut io).unwrap(), Status::NeedsInput);
+    let steps = vm.steps();
+    assert_eq!(vm.run(&mut io).unwrap(), Status::NeedsInput);
+    assert_eq!(vm.steps(), steps);
+    io.push_input("a");
+    assert_eq!(vm.run(&mut io).unwrap(), Status::NeedsInput);
+    assert_eq!(io.output(), "a");
+    io.close_input();
+    // `@` at EOF returns `v`, and `|` reprints nothing.
+    assert_eq!(
+        vm.run(&mut io).unwrap(),
+        Status::Finished(Rc::new(Function::V))
+    );
+    assert_eq!(io.output(), "a");
+}
+
+#[test]
+fn test_vm_spans() {
+    setup_logging();
+    let mut io = StringIo::new("");
+    let mut vm = Vm::new("# comment\n`.x\n  i").unwrap();
+    assert_eq!(
+        vm.span_at(vm.pc()),
+        Some(Span {
+            start: (1, 1),
+            end: (1, 2)
+        })
+    );
+    vm.step(&mut io).unwrap();
+    assert_eq!(
+        vm.span_at(vm.pc()),
+        Some(Span {
+            start: (1, 0),
+            end: (2, 2)
+        })
+    );
+    assert_eq!(vm.stack(), [Rc::new(Function::Dot('x'))]);
+    assert!(vm.return_stack().is_empty());
+}
+
+#[test]
+fn test_display_function() {
+    setup_logging();
+    assert_eq!(
+        parse_compile_run("``s`kv``s.ai").unwrap().to_string(),
+        "``s`kv``s.ai"
+    );
+    assert_eq!(parse_compile_run("```sddk").unwrap().to_string(), "`k`dk");
+    assert_eq!(
+        parse_compile_run("`d`ir").unwrap().to_string(),
+        "`d<promise>"
+    );
+    assert_eq!(
+        parse_compile_run("`ci").unwrap().to_string(),
+        "<continuation>"
+    );
+}
+
+struct FailingWriter;
+
+impl Write for FailingWriter {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fails the first `write_failures` writes and `flush_failures` flushes, then keeps what is
+/// written.
+#[derive(Default)]
+struct FlakyWriter {
+    write_failures: usize,
+    flush_failures: usize,
+    written: Vec<u8>,
+}
+
+impl Write for FlakyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write_failures > 0 {
+            self.write_failures -= 1;
+            return Err(io::ErrorKind::Other.into());
+        }
+        self.written.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.flush_failures > 0 {
+            self.flush_failures -= 1;
+            return Err(io::ErrorKind::Other.into());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_vm_io_error_retry() {
+    setup_logging();
+    let mut io = ReadWriteIo::new(io::empty(), FailingWriter);
+    let mut vm = Vm::new("`.ai").unwrap();
+    for _ in 0..2 {
+        assert!(matches!(vm.run(&mut io), Err(Error::Io { .. })));
+    }
+    let mut io = ReadWriteIo::new(FailingReader, io::sink());
+    let mut vm = Vm::new("`@i").unwrap();
+    for _ in 0..2 {
+        assert!(matches!(vm.run(&mut io), Err(Error::Io { .. })));
+    }
+
+    let writer = FlakyWriter {
+        write_failures: 1,
+        ..FlakyWriter::default()
+    };
+    let mut io = ReadWriteIo::new(io::empty(), writer);
+    let mut vm = Vm::new("`.ai").unwrap();
+    assert!(matches!(vm.run(&mut io), Err(Error::Io { .. })));
+    assert!(matches!(vm.run(&mut io), Ok(Status::Finished(v)) if *v == Function::I));
+    assert_eq!(io.writer().written, b"a");
+
+    // Output is flushed when the program exits through `e` and when it finishes.
+    for (code, exited) in &[("``.aei", true), ("`.ai", false)] {
+        let writer = FlakyWriter {
+            flush_failures: 1,
+            ..FlakyWriter::default()
+        };
+        let mut io = ReadWriteIo::new(io::empty(), writer);
+        let mut vm = Vm::new(code).unwrap();
+        assert!(matches!(vm.run(&mut io), Err(Error::Io { .. })));
+        match vm.run(&mut io) {
+            Ok(Status::Exited(_)) if *exited => (),
+            Ok(Status::Finished(_)) if !*exited => (),
+            r => panic!("expected the program to terminate, got {:?}", r),
+        }
+        assert_eq!(io.writer().written, b"a");
+    }
+}
+
+#[test]
+fn test_runtime_error_spans() {
+    setup_logging();
+    let mut io = ReadWriteIo::new(io::empty(), FailingWriter);
+    match parse_compile_run_with_io("`k\n `.xi", &mut io) {
+        Err(Error::Io {
+            error,
+            span: Some(span),
+        }) => {
+            assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+            assert_eq!(span.start, (1, 1));
+        }
+        r => panic!("expected I/O error, got {:?}", r),
+    }
+
+    let mut vm = Vm::new("```sii\n``sii").unwrap();
+    vm.set_limits(RunLimits {
+        max_steps: Some(3),
+        ..Default::default()
+    });
+    let err = vm.run(&mut StringIo::new("")).unwrap_err();
+    assert_eq!(err.span(), vm.span_at(vm.pc()));
+    assert_eq!(
+        err.to_string(),
+        "step limit exceeded after 3 steps at (0, 2)"
+    );
+}
+
+fn run_to_unlambda(code: &str) -> String {
+    let mut vm = Vm::new(code).unwrap();
+    match vm.run(&mut StringIo::new("")).unwrap() {
+        Status::Finished(v) | Status::Exited(v) => v.to_unlambda(vm.code()),
+        status => panic!("unexpected status {:?}", status),
+    }
+}
+
+#[test]
+fn test_to_unlambda() {
+    setup_logging();
+    assert_eq!(run_to_unlambda("`d`ir"), "`d`ir");
+    assert_eq!(run_to_unlambda("`k`d```s.ai`kr"), "`k`d```s.ai`kr");
+    assert_eq!(run_to_unlambda("```s`kd`k.xi"), "`d``k.xi");
+    assert_eq!(run_to_unlambda("``cir"), "r");
+    assert_eq!(run_to_unlambda("``k?\ni"), "?\n");
+    assert_eq!(run_to_unlambda("`ci"), "<continuation>");
+    // Rendered values evaluate back to themselves.
+    let promise = run_to_unlambda("`d``s.ai");
+    assert_eq!(run_to_unlambda(&format!("`{}k", promise)), "`kk");
+}
+
+fn parse_str(code: &str) -> SyntaxTree {
+    parse_toplevel(&mut CharPosIterator::new(code.chars()).peekable()).unwrap()
+}
+
+#[test]
+fn test_unparse() {
+    setup_logging();
+    let st = parse_str("# comment\n``S `K.\n `.x\n `?? [ii");
+    assert_eq!(unparse(&st, Layout::Canonical), "``s`kr`.x`??`ii");
+    assert_eq!(
+        unparse(&st, Layout::Indented),
+        "`\n  `\n    s\n    `kr\n  `\n    .x\n    `\n      ??\n      `ii"
+    );
+    for layout in [Layout::Canonical, Layout::Indented].iter() {
+        let reparsed = parse_str(&unparse(&st, *layout));
+        assert_eq!(
+            unparse(&reparsed, Layout::Canonical),
+            unparse(&st, Layout::Canonical)
+        );
+    }
+}
+
+#[test]
+fn test_parse_recovering() {
+    setup_logging();
+    let recover = |s: &str| parse_recovering(&mut CharPosIterator::new(s.chars()).peekable());
+
+    let (st, errors) = recover("``kis");
+    assert!(errors.is_empty());
+    assert_eq!(st, parse_str("``kis"));
+
+    let (st, errors) = recover("``kx\n`,`sk");
+    assert_eq!(errors.len(), 2);
+    assert!(matches!(
+        errors[0],
+        Error::UnexpectedToken {
+            ch: 'x',
+            position: (0, 3)
+        }
+    ));
+    assert!(matches!(
+        errors[1],
+        Error::UnexpectedToken {
+            ch: ',',
+            position: (1, 1)
+        }
+    ));
+    assert_eq!(unparse(&st, Layout::Canonical), "``k<error>`<error>`sk");
+    assert!(matches!(
+        Vm::from_syntax_tree(&st),
+        Err(Error::Syntax {
+            span: Span { start: (0, 3), .. }
+        })
+    ));
+
+    // Only the innermost application missing an operand is blamed for the end of input.
+    let (_, errors) = recover("``k`k");
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(
+        errors[0],
+        Error::UnexpectedEof {
+            after: Some('`'),
+            position: Some((0, 3))
+        }
+    ));
+
+    let (_, errors) = recover("`k?");
+    assert!(matches!(
+        errors[..],
+        [Error::UnexpectedEof {
+            after: Some('?'),
+            position: Some((0, 2))
+        }]
+    ));
+
+    let (_, errors) = recover("`kx ii");
+    assert!(matches!(
+        errors[..],
+        [
+            Error::UnexpectedToken { ch: 'x', .. },
+            Error::TrailingInput {
+                ch: 'i',
+                position: (0, 4)
+            }
+        ]
+    ));
+}
+
+struct FailingReader;
+
+impl Read for FailingReader {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::ConnectionReset.into())
+    }
+}
+
+#[test]
+fn test_streaming_parse() {
+    setup_logging();
+    let source = "``s # comment\n`k.\u{e9}`ki";
+    assert_eq!(parse_chars(source.chars()).unwrap(), parse_str(source));
+    assert_eq!(parse_reader(source.as_bytes()).unwrap(), parse_str(source));
+    assert!(matches!(
+        parse_reader("``k\n .".as_bytes()),
+        Err(Error::UnexpectedEof {
+            after: Some('.'),
+            position: Some((1, 1))
+        })
+    ));
+    assert!(matches!(
+        parse_reader(&b"`k\xffi"[..]),
+        Err(Error::Io { .. })
+    ));
+    assert!(matches!(
+        parse_reader("``k".as_bytes().chain(FailingReader)),
+        Err(Error::Io { error, .. }) if error.kind() == io::ErrorKind::ConnectionReset
+    ));
+
+    let st = parse_reader("``.a.bi".as_bytes()).unwrap();
+    let mut io = StringIo::new("");
+    Vm::from_syntax_tree(&st).unwrap().run(&mut io).unwrap();
+    assert_eq!(io.output(), "ab");
+}
+
+#[test]
+fn test_deep_nesting() {
+    setup_logging();
+    // Tracing a million steps would take forever.
+    log::set_max_level(log::LevelFilter::Info);
+    const DEPTH: usize = 1_000_000;
+
+    let left_spine = format!("{}i{}", "`".repeat(DEPTH), "i".repeat(DEPTH));
+    assert_eq!(parse_compile_run(&left_spine).unwrap(), Function::I);
+
+    let right_spine = format!("{}i", "`i".repeat(DEPTH));
+    let st = parse_str(&right_spine);
+    assert_eq!(st.span().end, (0, 2 * DEPTH));
+    let mut vm = Vm::from_syntax_tree(&st).unwrap();
+    let applications = (0..vm.code().len())
+        .filter(|&pc| vm.code()[pc] == OpCode::Invoke && vm.span_at(pc).is_some())
+        .count();
+    assert_eq!(applications, DEPTH);
+    drop(st);
+    assert!(matches!(vm.run(&mut StringIo::new("")), Ok(Status::Finished(v)) if *v == Function::I));
+
+    let (st, errors) = parse_recovering(&mut CharPosIterator::new(left_spine.chars()).peekable());
+    assert!(errors.is_empty());
+    assert_eq!(unparse(&st, Layout::Canonical), left_spine);
+    drop(st);
+    let truncated = &left_spine[..left_spine.len() - 1];
+    let (_, errors) = parse_recovering(&mut CharPosIterator::new(truncated.chars()).peekable());
+    assert_eq!(errors.len(), 1);
+
+    let cst = parse_cst(&mut CharPosIterator::new(left_spine.chars()).peekable()).unwrap();
+    assert_eq!(unparse_cst(&cst, Layout::Canonical), left_spine);
+    assert_eq!(cst.to_string(), left_spine);
+    assert_eq!(cst.to_syntax_tree().span().end, (0, 2 * DEPTH));
+}
+
+fn parse_cst_str(s: &str) -> Cst {
+    parse_cst(&mut CharPosIterator::new(s.chars()).peekable()).unwrap()
+}
+
+#[test]
+fn test_cst() {
+    setup_logging();
+    let source = "# header\n\n``S # the s\n  `ki\t# k of i\n\n  .x\n# done\n";
+    let cst = parse_cst_str(source);
+    assert_eq!(cst.to_string(), source);
+    assert_eq!(cst.to_syntax_tree(), parse_str(source));
+    assert_eq!(
+        cst.trailing.last(),
+        Some(&Trivia::Whitespace("\n".to_string()))
+    );
+    match &cst.root.kind {
+        CstKind::Application { backquote, .. } => assert_eq!(
+            backquote.leading,
+            vec![
+                Trivia::Comment("# header".to_string()),
+                Trivia::Whitespace("\n\n".to_string())
+            ]
+        ),
+        k => panic!("expected an application, got {:?}", k),
+    }
+
+    assert_eq!(
+        unparse_cst(&cst, Layout::Canonical),
+        "# header\n``s # the s\n`ki # k of i\n.x\n# done"
+    );
+    assert_eq!(
+        unparse_cst(&cst, Layout::Indented),
+        "# header\n`\n  `\n    s # the s\n    `\n      k\n      i # k of i\n  .x\n# done"
+    );
+    for layout in [Layout::Canonical, Layout::Indented].iter() {
+        let reparsed = parse_cst_str(&unparse_cst(&cst, *layout));
+        assert_eq!(unparse_cst(&reparsed, *layout), unparse_cst(&cst, *layout));
+    }
+
+    assert!(matches!(
+        parse_cst(&mut CharPosIterator::new("`k # oops".chars()).peekable()),
+        Err(Error::UnexpectedEof {
+            after: Some('`'),
+            position: Some((0, 0))
+        })
+    ));
+}
+
+#[test]
+fn test_references() {
+    setup_logging();
+    let st = parse_str("``$two_1 K $x");
+    match &st {
+        SyntaxTree::Application(app) => match &*app.func {
+            SyntaxTree::Application(inner) => assert_eq!(
+                *inner.func,
+                SyntaxTree::Reference(
+                    "two_1".to_string(),
+                    Span {
+                        start: (0, 2),
+                        end: (0, 7)
+                    }
+                )
+            ),
+            st => panic!("expected an application, got {:?}", st),
+        },
+        st => panic!("expected an application, got {:?}", st),
+    }
+    // A reference is kept apart from a combinator that would otherwise extend its name.
+    assert_eq!(unparse(&st, Layout::Canonical), "``$two_1 k$x");
+    assert_eq!(
+        unparse(
+            &parse_str(&unparse(&st, Layout::Canonical)),
+            Layout::Indented
+        ),
+        unparse(&st, Layout::Indented)
+    );
+    let cst = parse_cst_str("`$f # f\n  i");
+    assert_eq!(unparse_cst(&cst, Layout::Canonical), "`$f # f\ni");
+    assert_eq!(
+        unparse_cst(&parse_cst_str("`$f\ti"), Layout::Canonical),
+        "`$f i"
+    );
+
+    assert!(matches!(
+        Vm::new("`$f i"),
+        Err(Error::UnboundReference { ref name, span }) if name == "f" && span.start == (0, 1)
+    ));
+    assert!(matches!(
+        parse_chars("`$ i".chars()),
+        Err(Error::UnexpectedToken {
+            ch: ' ',
+            position: (0, 2)
+        })
+    ));
+    assert!(matches!(
+        parse_chars("`i$".chars()),
+        Err(Error::UnexpectedEof {
+            after: Some('$'),
+            position: Some((0, 2))
+        })
+    ));
 }
 
 // Examples I found bugs with at some point
@@ -103,15 +1028,15 @@ fn test_s_d() {
 fn tests_random_stuff() {
     setup_logging();
     assert_eq!(
-        parse_compile_run(&"`r```s``si`k.*`kid").unwrap(),
+        parse_compile_run("`r```s``si`k.*`kid").unwrap(),
         Function::I
     );
     assert_eq!(
-        parse_compile_run(&"`r```s``s`kd`k.*`kii").unwrap(),
+        parse_compile_run("`r```s``s`kd`k.*`kii").unwrap(),
         Function::I
     );
     assert_eq!(
-        parse_compile_run(&"`r```sd``s`k.*`kid").unwrap(),
+        parse_compile_run("`r```sd``s`k.*`kid").unwrap(),
         Function::D1(Expression::Function(Rc::new(Function::I)))
     )
 }